@@ -0,0 +1,137 @@
+#!/usr/bin/env python3
+"""Load generator for comparing Shallot builds.
+
+Measures request throughput and latency for plain HTTP requests through the
+proxy, and the memory and CPU cost of holding idle CONNECT tunnels open.
+Start an origin server and the proxy, then run the benchmark:
+
+    python3 bench/proxy_bench.py --serve 8002 &
+    python3 bench/proxy_bench.py --target 127.0.0.1:8002 --pid $(pidof shallot)
+
+The origin answers every request with a small fixed response and has a large
+listen backlog, so it isn't the bottleneck the way `python3 -m http.server` is.
+"""
+
+import argparse
+import asyncio
+import time
+
+
+def parse_addr(addr):
+    host, port = addr.rsplit(":", 1)
+    return host, int(port)
+
+
+async def one_request(proxy, target):
+    reader, writer = await asyncio.open_connection(*proxy)
+    writer.write(
+        f"GET http://{target[0]}:{target[1]}/ HTTP/1.1\r\n"
+        f"Host: {target[0]}:{target[1]}\r\n\r\n".encode()
+    )
+    await writer.drain()
+    data = await reader.read()
+    writer.close()
+    if not data.startswith(b"HTTP/1.") or b" 200 " not in data.split(b"\r\n", 1)[0]:
+        raise RuntimeError(data[:80])
+
+
+async def requests(proxy, target, total, concurrency):
+    latencies = []
+    errors = 0
+    remaining = iter(range(total))
+
+    async def client():
+        nonlocal errors
+        for _ in remaining:
+            start = time.perf_counter()
+            try:
+                await one_request(proxy, target)
+                latencies.append(time.perf_counter() - start)
+            except Exception:
+                errors += 1
+
+    start = time.perf_counter()
+    await asyncio.gather(*(client() for _ in range(concurrency)))
+    elapsed = time.perf_counter() - start
+
+    latencies.sort()
+    pct = lambda p: latencies[min(len(latencies) - 1, int(len(latencies) * p))] * 1000
+    print(f"requests: {len(latencies)} ok, {errors} failed in {elapsed:.2f}s "
+          f"= {len(latencies) / elapsed:.0f} req/s, "
+          f"p50 {pct(0.5):.1f} ms, p99 {pct(0.99):.1f} ms")
+
+
+async def serve_origin(port):
+    body = b"x" * 1024
+    response = b"HTTP/1.1 200 OK\r\nContent-Length: %d\r\nConnection: close\r\n\r\n%s" % (len(body), body)
+
+    async def handle(reader, writer):
+        try:
+            await reader.readuntil(b"\r\n\r\n")
+            writer.write(response)
+            await writer.drain()
+        except Exception:
+            pass
+        writer.close()
+
+    server = await asyncio.start_server(handle, "127.0.0.1", port, backlog=4096)
+    await server.serve_forever()
+
+
+def proc_stats(pid):
+    with open(f"/proc/{pid}/status") as f:
+        rss = next(int(l.split()[1]) for l in f if l.startswith("VmRSS"))
+    with open(f"/proc/{pid}/stat") as f:
+        fields = f.read().rsplit(")", 1)[1].split()
+        ticks = int(fields[11]) + int(fields[12])
+    with open(f"/proc/{pid}/status") as f:
+        threads = next(int(l.split()[1]) for l in f if l.startswith("Threads"))
+    return rss, ticks, threads
+
+
+async def idle_tunnels(proxy, target, count, pid):
+    streams = []
+    for _ in range(count):
+        reader, writer = await asyncio.open_connection(*proxy)
+        writer.write(f"CONNECT {target[0]}:{target[1]} HTTP/1.1\r\n\r\n".encode())
+        await writer.drain()
+        await reader.readuntil(b"\r\n\r\n")
+        streams.append(writer)
+
+    if pid:
+        rss, ticks, threads = proc_stats(pid)
+        await asyncio.sleep(5)
+        _, ticks_after, _ = proc_stats(pid)
+        print(f"idle tunnels: {count} open, {rss / 1024:.1f} MiB RSS, {threads} threads, "
+              f"{(ticks_after - ticks) / 5:.1f} CPU ticks/s while idle")
+    else:
+        print(f"idle tunnels: {count} open")
+
+    for writer in streams:
+        writer.close()
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("--proxy", default="127.0.0.1:7878")
+    parser.add_argument("--serve", type=int, metavar="PORT", help="run the origin server instead")
+    parser.add_argument("--target", help="HTTP server to request / from, as host:port")
+    parser.add_argument("--requests", type=int, default=2000)
+    parser.add_argument("--concurrency", type=int, default=50)
+    parser.add_argument("--tunnels", type=int, default=200)
+    parser.add_argument("--pid", type=int, help="proxy process, to report its memory and CPU use")
+    args = parser.parse_args()
+
+    if args.serve:
+        asyncio.run(serve_origin(args.serve))
+        return
+    if not args.target:
+        parser.error("--target is required")
+
+    proxy, target = parse_addr(args.proxy), parse_addr(args.target)
+    asyncio.run(requests(proxy, target, args.requests, args.concurrency))
+    asyncio.run(idle_tunnels(proxy, target, args.tunnels, args.pid))
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,163 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::OnceLock;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::logging;
+use crate::logging::Event;
+use crate::proxy_listener::ProxyError;
+use crate::proxy_listener::Result;
+
+/// Places for tunnels, shared by every listener. Unlimited until `limit_tunnels` is called.
+static TUNNELS: OnceLock<Arc<Semaphore>> = OnceLock::new();
+
+tokio::task_local! {
+    // Place and turn of the connection handled by the current task, until it finishes or releases them
+    static PLACE: RefCell<Option<(Admitted, OwnedSemaphorePermit)>>;
+}
+
+/// Limits how many connections are handled at once. Up to `workers` connections are handled at the same time and up
+/// to `queue` more wait for their turn. Connections beyond that are refused so the caller can turn the client away,
+/// which keeps a flood of connections from using up memory.
+#[derive(Debug, Clone)]
+pub struct Admission {
+    // Permits for connections being handled or waiting
+    admitted: Arc<Semaphore>,
+    // Permits for connections being handled
+    active: Arc<Semaphore>,
+}
+
+/// A connection that has been admitted, and holds its place until dropped
+#[derive(Debug)]
+pub struct Admitted {
+    _slot: OwnedSemaphorePermit,
+    active: Arc<Semaphore>,
+}
+
+impl Admission {
+    pub fn new(workers: usize, queue: usize) -> Admission {
+        Admission {
+            admitted: Arc::new(Semaphore::new(workers + queue)),
+            active: Arc::new(Semaphore::new(workers)),
+        }
+    }
+
+    /// Admit a connection if it can be handled now or there's room for it to wait. None if it has to be turned away.
+    pub fn try_admit(&self) -> Option<Admitted> {
+        let slot = Arc::clone(&self.admitted).try_acquire_owned().ok()?;
+        Some(Admitted {
+            _slot: slot,
+            active: Arc::clone(&self.active),
+        })
+    }
+}
+
+impl Admitted {
+    /// Wait until fewer than `workers` connections are being handled. The connection counts as handled until the
+    /// returned permit is dropped.
+    pub async fn wait_turn(&self) -> OwnedSemaphorePermit {
+        Arc::clone(&self.active)
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed")
+    }
+}
+
+/// A place among the open tunnels, held for as long as the tunnel is open
+#[derive(Debug, Default)]
+pub struct TunnelSlot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Limit how many tunnels are open at once across all listeners
+pub fn limit_tunnels(max: usize) {
+    let _ = TUNNELS.set(Arc::new(Semaphore::new(max)));
+}
+
+/// Take a place for a tunnel the client at `src_addr` asked for. Fails with Overloaded, which is logged, if all of them
+/// are taken. Taken before the client is told the tunnel is open, so it can still be refused.
+pub fn tunnel_slot(src_addr: &IpAddr) -> Result<TunnelSlot> {
+    match TUNNELS.get() {
+        Some(tunnels) => take_tunnel_slot(tunnels, src_addr),
+        None => Ok(TunnelSlot::default()),
+    }
+}
+
+fn take_tunnel_slot(tunnels: &Arc<Semaphore>, src_addr: &IpAddr) -> Result<TunnelSlot> {
+    match Arc::clone(tunnels).try_acquire_owned() {
+        Ok(permit) => Ok(TunnelSlot { _permit: Some(permit) }),
+        Err(_) => {
+            logging::event_log(
+                Event::Overloaded,
+                &format!("Too many tunnels open, refused a tunnel for {}", src_addr),
+            );
+            Err(ProxyError::Overloaded)
+        }
+    }
+}
+
+/// Handle a connection with its place and turn, which are held until the connection is done unless it releases them
+/// first.
+pub async fn hold<F: Future>(admitted: Admitted, turn: OwnedSemaphorePermit, f: F) -> F::Output {
+    PLACE.scope(RefCell::new(Some((admitted, turn))), f).await
+}
+
+/// Give up the place of the connection handled by the current task, letting the next connection in. Called once a
+/// connection becomes a tunnel, which can stay open for a long time while only moving bytes when either end sends
+/// some. The tunnel holds a TunnelSlot instead, so it's still counted. Outside of `hold` this does nothing.
+pub fn release() {
+    let _ = PLACE.try_with(|place| place.borrow_mut().take());
+}
+
+#[cfg(test)]
+mod test_admission {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_admission_limits() {
+        let admission = Admission::new(1, 1);
+
+        let first = admission.try_admit().unwrap();
+        let turn = first.wait_turn().await;
+
+        // The second connection is admitted but has to wait, a third is refused
+        let second = admission.try_admit().unwrap();
+        assert!(admission.try_admit().is_none());
+        assert!(Arc::clone(&second.active).try_acquire_owned().is_err());
+
+        drop(turn);
+        drop(first);
+        let _turn = second.wait_turn().await;
+        assert!(admission.try_admit().is_some());
+    }
+
+    #[test]
+    fn test_tunnel_slots() {
+        let tunnels = Arc::new(Semaphore::new(1));
+        let src_addr = "127.0.0.1".parse().unwrap();
+
+        let slot = take_tunnel_slot(&tunnels, &src_addr).unwrap();
+        assert!(matches!(take_tunnel_slot(&tunnels, &src_addr), Err(ProxyError::Overloaded)));
+        drop(slot);
+        assert!(take_tunnel_slot(&tunnels, &src_addr).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_release() {
+        let admission = Admission::new(1, 0);
+        let first = admission.try_admit().unwrap();
+        let turn = first.wait_turn().await;
+
+        hold(first, turn, async {
+            assert!(admission.try_admit().is_none());
+            release();
+            let second = admission.try_admit().unwrap();
+            drop(second.wait_turn().await);
+        })
+        .await;
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::hash::BuildHasher;
+use std::hash::RandomState;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::logging;
+use crate::logging::Event;
+
+/// Result of checking the Proxy-Authorization header of a request
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthResult {
+    /// No user file is present, so every request is let through
+    Disabled,
+    /// Credentials were verified for this user
+    User(String),
+    /// No credentials, or credentials that don't check out
+    Denied,
+}
+
+/// htpasswd style user store. Each line is `username:hash`, where the hash is
+/// bcrypt (`htpasswd -B`) or argon2 in PHC string format. If the file does
+/// not exist, authentication is disabled.
+///
+/// Checking a password against its hash is slow on purpose, so it runs on tokio's blocking threads, and credentials
+/// that checked out are remembered for as long as the store is in use. Only a keyed hash of each password is kept.
+#[derive(Debug, Clone)]
+pub struct UserStore {
+    // None if the file doesn't exist
+    users: Option<HashMap<String, String>>,
+    // Keyed hashes of the user and password pairs verified so far
+    verified: Arc<Mutex<HashSet<u64>>>,
+    hasher: RandomState,
+}
+
+impl UserStore {
+    pub fn new(path: &str) -> UserStore {
+        UserStore {
+            users: Self::load_users(path),
+            verified: Arc::new(Mutex::new(HashSet::new())),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Check the value of a Proxy-Authorization header
+    pub async fn authenticate(&self, proxy_authorization: Option<&str>) -> AuthResult {
+        if !self.enabled() {
+            return AuthResult::Disabled;
+        }
+
+        match proxy_authorization.and_then(parse_basic) {
+            Some((user, password)) => self.verify(&user, &password).await,
+            None => AuthResult::Denied,
+        }
+    }
+
+    /// Check a username and password given outside of HTTP, such as in the SOCKS5 username/password subnegotiation
+    pub async fn authenticate_user(&self, user: &str, password: &str) -> AuthResult {
+        match self.enabled() {
+            true => self.verify(user, password).await,
+            false => AuthResult::Disabled,
+        }
+    }
+
+    /// Returns true if clients have to authenticate
+    pub fn enabled(&self) -> bool {
+        self.users.is_some()
+    }
+
+    async fn verify(&self, user: &str, password: &str) -> AuthResult {
+        let hash = match self.users.as_ref().and_then(|users| users.get(user)) {
+            Some(hash) => hash.clone(),
+            None => return AuthResult::Denied,
+        };
+
+        let key = self.hasher.hash_one((user, password));
+        if self.verified.lock().unwrap().contains(&key) {
+            return AuthResult::User(user.to_owned());
+        }
+
+        let password = password.to_owned();
+        let valid = tokio::task::spawn_blocking(move || verify_password(&password, &hash))
+            .await
+            .unwrap_or(false);
+        match valid {
+            true => {
+                self.verified.lock().unwrap().insert(key);
+                AuthResult::User(user.to_owned())
+            }
+            false => AuthResult::Denied,
+        }
+    }
+
+    fn load_users(path: &str) -> Option<HashMap<String, String>> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            // Fail closed if the file is there but can't be read
+            Err(e) => {
+                logging::event_log(Event::ProxyServer, &format!("Could not read {}: {}", path, e));
+                return Some(HashMap::new());
+            }
+        };
+
+        let (users, errors) = parse_users(&content);
+        for (n, e) in errors {
+            logging::event_log(
+                Event::ProxyServer,
+                &format!("Ignoring {} line {}: {}", path, n, e),
+            );
+        }
+
+        Some(users)
+    }
+}
+
+/// Parse the contents of a user file. Lines that aren't `user:hash` are
+/// skipped and returned alongside the users with their line number.
+pub fn parse_users(content: &str) -> (HashMap<String, String>, Vec<(usize, String)>) {
+    let mut users = HashMap::new();
+    let mut errors = vec![];
+
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.split_once(':') {
+            Some((user, hash)) if !user.is_empty() && !hash.is_empty() => {
+                users.insert(user.to_owned(), hash.to_owned());
+            }
+            _ => errors.push((n + 1, "expected user:hash".to_owned())),
+        }
+    }
+
+    (users, errors)
+}
+
+/// Decode `Basic <base64(user:password)>` credentials
+fn parse_basic(header: &str) -> Option<(String, String)> {
+    let (scheme, token) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+
+    let decoded = STANDARD.decode(token.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+
+    Some((user.to_owned(), password.to_owned()))
+}
+
+/// Check a password against a bcrypt or argon2 hash. Any other hash format
+/// is refused rather than compared in plain text.
+fn verify_password(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$2") {
+        return bcrypt::verify(password, hash).unwrap_or(false);
+    }
+
+    if hash.starts_with("$argon2") {
+        return match PasswordHash::new(hash) {
+            Ok(parsed) => Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok(),
+            Err(_) => false,
+        };
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod test_auth {
+
+    use super::*;
+    use argon2::password_hash::{PasswordHasher, SaltString};
+    use argon2::{Algorithm, Params, Version};
+
+    #[test]
+    fn test_parse_basic() {
+        // alice:open sesame
+        let header = "Basic YWxpY2U6b3BlbiBzZXNhbWU=";
+        assert_eq!(
+            parse_basic(header),
+            Some(("alice".to_owned(), "open sesame".to_owned()))
+        );
+
+        assert_eq!(parse_basic("Bearer YWxpY2U6b3BlbiBzZXNhbWU="), None);
+        assert_eq!(parse_basic("Basic !!!"), None);
+    }
+
+    #[test]
+    fn test_verify_password() {
+        let hash = bcrypt::hash("hunter2", 4).unwrap();
+        assert!(verify_password("hunter2", &hash));
+        assert!(!verify_password("hunter3", &hash));
+
+        // Cheap parameters to keep the test fast, verification reads them from the hash
+        let params = Params::new(16, 2, 1, None).unwrap();
+        let salt = SaltString::encode_b64(b"shallot-test-salt").unwrap();
+        let hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(b"hunter2", &salt)
+            .unwrap()
+            .to_string();
+        assert!(verify_password("hunter2", &hash));
+        assert!(!verify_password("hunter3", &hash));
+
+        assert!(!verify_password("plain", "plain"));
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_caches() {
+        let path = std::env::temp_dir().join(format!("shallot_test_auth_{}.htpasswd", std::process::id()));
+        fs::write(&path, format!("alice:{}\n", bcrypt::hash("open sesame", 4).unwrap())).unwrap();
+        let store = UserStore::new(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+
+        // alice:open sesame
+        let header = Some("Basic YWxpY2U6b3BlbiBzZXNhbWU=");
+        assert_eq!(store.authenticate(header).await, AuthResult::User("alice".to_owned()));
+        assert_eq!(store.verified.lock().unwrap().len(), 1);
+        assert_eq!(store.authenticate_user("alice", "open sesame").await, AuthResult::User("alice".to_owned()));
+
+        // Failures aren't remembered
+        assert_eq!(store.authenticate_user("alice", "hunter2").await, AuthResult::Denied);
+        assert_eq!(store.authenticate_user("bob", "open sesame").await, AuthResult::Denied);
+        assert_eq!(store.authenticate(None).await, AuthResult::Denied);
+        assert_eq!(store.verified.lock().unwrap().len(), 1);
+
+        assert_eq!(UserStore::new("/nonexistent/users.htpasswd").authenticate(None).await, AuthResult::Disabled);
+    }
+}
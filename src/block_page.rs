@@ -0,0 +1,252 @@
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use arc_swap::ArcSwap;
+
+use crate::config::BlockPageConfig;
+use crate::firewall::modified;
+use crate::logging;
+use crate::logging::Event;
+
+/// Templates used when the configured files don't exist
+const DEFAULT_HTML: &str = include_str!("../block_page.html");
+const DEFAULT_JSON: &str = include_str!("../block_page.json");
+
+/// Counts block pages served, so request IDs stay unique within a millisecond
+static SERVED: AtomicU64 = AtomicU64::new(0);
+
+/// What a block page tells the client about the request that was denied
+#[derive(Debug)]
+pub struct Denial<'a> {
+    pub host: &'a str,
+    pub reason: &'a str,
+    pub request_id: &'a str,
+}
+
+/// The HTML and JSON block page templates as loaded at one point in time
+#[derive(Debug)]
+pub struct BlockPage {
+    html: String,
+    json: String,
+    contact: String,
+}
+
+/// The current block page templates. Like the SharedFirewall, the template files are watched and reloaded when they
+/// change, so the page can be edited while the server is running.
+#[derive(Debug)]
+pub struct SharedBlockPage {
+    config: BlockPageConfig,
+    current: ArcSwap<BlockPage>,
+    // Modification times of the HTML and JSON templates the current page was loaded from
+    loaded: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl SharedBlockPage {
+    pub fn new(config: &BlockPageConfig) -> SharedBlockPage {
+        SharedBlockPage {
+            loaded: Mutex::new(file_times(config)),
+            current: ArcSwap::from_pointee(BlockPage::load(config)),
+            config: config.clone(),
+        }
+    }
+
+    /// Returns the current templates
+    pub fn page(&self) -> Arc<BlockPage> {
+        self.current.load_full()
+    }
+
+    /// Load the templates again if either file changed. Like SharedFirewall::reload, this is run by `watch`.
+    pub fn reload(&self) {
+        let times = file_times(&self.config);
+        let mut loaded = self.loaded.lock().unwrap();
+
+        if *loaded != times {
+            self.current.store(Arc::new(BlockPage::load(&self.config)));
+            *loaded = times;
+            logging::event_log(Event::ProxyServer, "Reloaded block page templates");
+        }
+    }
+}
+
+impl BlockPage {
+    fn load(config: &BlockPageConfig) -> BlockPage {
+        BlockPage {
+            html: load_template(&config.html, DEFAULT_HTML),
+            json: load_template(&config.json, DEFAULT_JSON),
+            contact: config.contact.clone(),
+        }
+    }
+
+    /// Render the page for a denial, as JSON if the Accept header prefers it over HTML. Returns the content type and
+    /// the body.
+    pub fn render(&self, denial: &Denial, accept: Option<&str>) -> (&'static str, String) {
+        match prefers_json(accept.unwrap_or("")) {
+            true => (
+                "application/json",
+                fill(&self.json, denial, &self.contact, escape_json),
+            ),
+            false => (
+                "text/html; charset=utf-8",
+                fill(&self.html, denial, &self.contact, escape_html),
+            ),
+        }
+    }
+}
+
+/// A new ID for a denied request, to be quoted by the user and looked up in the event log
+pub fn request_id() -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    let n = SERVED.fetch_add(1, Ordering::Relaxed);
+
+    format!("{:x}-{:04x}", millis, n & 0xffff)
+}
+
+/// A template file that doesn't exist falls back to the built in one. One that can't be read is noted in the event log
+/// and falls back too, since a denial has to be answered either way.
+fn load_template(path: &str, default: &str) -> String {
+    match fs::read_to_string(path) {
+        Ok(template) => template,
+        Err(e) => {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                logging::event_log(Event::ProxyServer, &format!("Could not read {}: {}", path, e));
+            }
+            default.to_owned()
+        }
+    }
+}
+
+fn file_times(config: &BlockPageConfig) -> (Option<SystemTime>, Option<SystemTime>) {
+    (modified(&config.html), modified(&config.json))
+}
+
+/// Replace the {{host}}, {{reason}}, {{request_id}} and {{contact}} placeholders, escaping the values for the template's
+/// format since the host comes from the client. The template is scanned once, so a value that looks like a placeholder
+/// is left as it is.
+fn fill(template: &str, denial: &Denial, contact: &str, escape: fn(&str) -> String) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = match rest.find("}}") {
+            Some(end) => end,
+            None => break,
+        };
+        let value = match &rest[2..end] {
+            "host" => denial.host,
+            "reason" => denial.reason,
+            "request_id" => denial.request_id,
+            "contact" => contact,
+            _ => {
+                filled.push_str("{{");
+                rest = &rest[2..];
+                continue;
+            }
+        };
+        filled.push_str(&escape(value));
+        rest = &rest[end + 2..];
+    }
+
+    filled.push_str(rest);
+    filled
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escape text for use inside a JSON string
+fn escape_json(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// True if an Accept header ranks JSON above HTML. Without a preference for either, clients get HTML.
+fn prefers_json(accept: &str) -> bool {
+    let mut json = 0.0;
+    let mut html = 0.0;
+
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let media_type = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        match media_type.as_str() {
+            "application/json" | "application/*" => json = q.max(json),
+            "text/html" | "text/*" | "*/*" => html = q.max(html),
+            _ => {}
+        }
+    }
+
+    json > html
+}
+
+#[cfg(test)]
+mod test_block_page {
+
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let page = BlockPage {
+            html: "<p>{{host}}: {{reason}} ({{request_id}}) {{contact}}</p>".to_owned(),
+            json: "{\"host\": \"{{host}}\"}".to_owned(),
+            contact: "mailto:admin@example.com".to_owned(),
+        };
+        let denial = Denial {
+            host: "<evil>\".{{reason}}",
+            reason: "blacklisted",
+            request_id: "1-0000",
+        };
+
+        let (content_type, body) = page.render(&denial, None);
+        assert_eq!(content_type, "text/html; charset=utf-8");
+        assert_eq!(
+            body,
+            "<p>&lt;evil&gt;&quot;.{{reason}}: blacklisted (1-0000) mailto:admin@example.com</p>"
+        );
+
+        let (content_type, body) = page.render(&denial, Some("application/json"));
+        assert_eq!(content_type, "application/json");
+        assert_eq!(body, "{\"host\": \"<evil>\\\".{{reason}}\"}");
+    }
+
+    #[test]
+    fn test_prefers_json() {
+        assert!(prefers_json("application/json"));
+        assert!(prefers_json("text/html;q=0.5, application/json"));
+        assert!(!prefers_json("text/html,application/xhtml+xml,*/*;q=0.8"));
+        assert!(!prefers_json("application/json;q=0.5, */*"));
+        assert!(!prefers_json(""));
+    }
+}
@@ -3,17 +3,21 @@
 
 use memcache::MemcacheError;
 
+use crate::config::CacheConfig;
+
 pub struct Cache {
     client: memcache::Client,
 }
 
 impl Cache {
     /// Connects to the memcache server and flushes cache from the previous server run.
-    /// The default memcache port is 11211. If the server is not running on this port, the thread will hang, and throw
-    /// a MemcacheError after 5 seconds.
-    pub fn new() -> Result<Cache, MemcacheError> {
-        // Connect to the memcache server. Default port is 11211.
-        let client = memcache::connect("memcache://127.0.0.1:11211?timeout=5&tcp_nodelay=true")?;
+    /// The server address and timeout come from the [cache] section of the configuration. If the server is not
+    /// running there, the thread will hang, and throw a MemcacheError after the timeout.
+    pub fn new(config: &CacheConfig) -> Result<Cache, MemcacheError> {
+        let client = memcache::connect(format!(
+            "memcache://{}?timeout={}&tcp_nodelay=true",
+            config.address, config.timeout_secs
+        ))?;
         // Cache should be cleared of the previous session's data.
         client.flush()?;
         Ok(Cache {
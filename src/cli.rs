@@ -0,0 +1,263 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::net::IpAddr;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::thread;
+
+use clap::{Parser, Subcommand};
+use regex::RegexBuilder;
+
+use crate::config::Config;
+use crate::firewall;
+use crate::firewall::Firewall;
+use crate::logging;
+use crate::policy::{Action, Client, Destination};
+use crate::proxy_listener;
+use crate::sorting_logs;
+use crate::statistics;
+
+#[derive(Debug, Parser)]
+#[command(name = "shallot", version, about = "A filtering HTTP proxy")]
+pub struct Cli {
+    /// Configuration file, shallot.toml in the working directory by default
+    #[arg(short, long, global = true)]
+    pub config: Option<String>,
+
+    /// Runs the server when no command is given
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the proxy server
+    Serve,
+    /// Check the configuration and the list, policy and user files, then exit
+    CheckConfig,
+    /// Print statistics for the current event log
+    Stats,
+    /// Sort and search the logs
+    Logs {
+        #[command(subcommand)]
+        command: LogsCommand,
+    },
+    /// Inspect the whitelist, blacklist and policy
+    Lists {
+        #[command(subcommand)]
+        command: ListsCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum LogsCommand {
+    /// Print the connection log sorted by source address and time
+    Sort {
+        /// Write to this file instead of standard output
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Print the event log lines matching a regular expression
+    Grep {
+        pattern: String,
+        #[arg(short, long)]
+        ignore_case: bool,
+        /// Search the connection log instead
+        #[arg(long)]
+        connections: bool,
+    },
+    /// Print the last lines of the event log
+    Tail {
+        #[arg(short = 'n', long, default_value_t = 10)]
+        lines: usize,
+        /// Print the connection log instead
+        #[arg(long)]
+        connections: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ListsCommand {
+    /// Show which rules match a destination address or hostname
+    Test {
+        /// Address or hostname of the destination
+        target: String,
+        /// Destination port, for policy rules
+        #[arg(short, long, default_value_t = 80)]
+        port: u16,
+        /// Client address, for the whitelist and policy rules
+        #[arg(long, default_value = "127.0.0.1")]
+        from: IpAddr,
+        /// Authenticated user, for policy rules
+        #[arg(short, long)]
+        user: Option<String>,
+        /// Also check the addresses the hostname resolves to, as the proxy does
+        #[arg(short, long)]
+        resolve: bool,
+        /// Protocol the request asks to upgrade to, such as websocket, for policy rules
+        #[arg(long)]
+        upgrade: Option<String>,
+    },
+}
+
+/// Run a command and return the exit status
+pub fn run(command: Command, config: Config) -> i32 {
+    logging::init(&config.logging);
+
+    match command {
+        Command::Serve => serve(config),
+        Command::CheckConfig => check_config(&config),
+        Command::Stats => {
+            println!("{}", statistics::current_statistics(&config.logging));
+            0
+        }
+        Command::Logs { command } => logs(command, &config),
+        Command::Lists { command } => lists(command, &config),
+    }
+}
+
+fn serve(config: Config) -> i32 {
+    let config = Arc::new(config);
+
+    // Run generate_statistics in a background thread which generates the stats from the event log
+    // periodically into the statistics file.
+    let stats_config = config.logging.clone();
+    thread::spawn(move || {
+        statistics::generate_statistics(stats_config);
+    });
+
+    // Block the runtime on the proxy listener
+    if let Err(e) = proxy_listener::run_listener(config) {
+        eprintln!("{:?}", e);
+        return 1;
+    }
+    println!("Terminating server!");
+    0
+}
+
+/// The configuration itself has been validated by the time this runs, so only the files it names are left to check
+fn check_config(config: &Config) -> i32 {
+    let problems = firewall::check_files(&config.lists);
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+
+    match problems.is_empty() {
+        true => {
+            println!("Configuration OK");
+            0
+        }
+        false => 1,
+    }
+}
+
+fn logs(command: LogsCommand, config: &Config) -> i32 {
+    let result = match command {
+        LogsCommand::Sort { output } => sorting_logs::sort_logs(&config.logging.connection_log)
+            .and_then(|entries| match output {
+                Some(path) => sorting_logs::write_sorted_logs(&entries, &mut File::create(path)?),
+                None => sorting_logs::write_sorted_logs(&entries, &mut io::stdout().lock()),
+            }),
+
+        LogsCommand::Grep { pattern, ignore_case, connections } => {
+            let re = match RegexBuilder::new(&pattern).case_insensitive(ignore_case).build() {
+                Ok(re) => re,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return 2;
+                }
+            };
+            read_log(config, connections).map(|log| {
+                log.lines().filter(|line| re.is_match(line)).for_each(|line| println!("{}", line))
+            })
+        }
+
+        LogsCommand::Tail { lines, connections } => read_log(config, connections).map(|log| {
+            let all: Vec<&str> = log.lines().collect();
+            all[all.len().saturating_sub(lines)..].iter().for_each(|line| println!("{}", line))
+        }),
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+fn read_log(config: &Config, connections: bool) -> io::Result<String> {
+    let path = match connections {
+        true => &config.logging.connection_log,
+        false => &config.logging.event_log,
+    };
+    fs::read_to_string(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
+fn lists(command: ListsCommand, config: &Config) -> i32 {
+    let ListsCommand::Test { target, port, from, user, resolve, upgrade } = command;
+    let fwall = Firewall::new(&config.lists);
+    let target = target.trim_start_matches('[').trim_end_matches(']').to_owned();
+
+    let mut addrs: Vec<IpAddr> = target.parse().into_iter().collect();
+    if resolve && addrs.is_empty() {
+        match (target.as_str(), port).to_socket_addrs() {
+            Ok(resolved) => addrs.extend(resolved.map(|a| a.ip())),
+            Err(_) => {
+                eprintln!("Could not resolve {}", target);
+                return 1;
+            }
+        }
+    }
+    let hosts: Vec<String> = match addrs.first() {
+        Some(addr) if addr.to_string() == target => vec![],
+        _ => vec![target.clone()],
+    };
+
+    if fwall.policy_enabled() {
+        let client = Client { user: user.as_deref(), addr: from };
+        let upgrades: Vec<String> = upgrade.iter().map(|u| u.to_ascii_lowercase()).collect();
+        let dst = Destination { hosts: &hosts, addrs: &addrs, port, upgrades: &upgrades };
+        let verdict = match fwall.evaluate_policy(&client, &dst) {
+            Some(verdict) => verdict,
+            None => return 1,
+        };
+
+        // Show the rules as written, comments and all
+        let content = fs::read_to_string(&config.lists.policy).unwrap_or_default();
+        let rule = |n: usize| content.lines().nth(n - 1).unwrap_or("").trim().to_owned();
+
+        for n in &verdict.logged {
+            println!("{} line {}: {}", config.lists.policy, n, rule(*n));
+        }
+        let action = match verdict.action {
+            Action::Deny => "denied",
+            _ => "allowed",
+        };
+        match verdict.line {
+            Some(n) => println!("{} line {}: {} -> {}", config.lists.policy, n, rule(n), action),
+            None => println!("{}: no rule matched, {} by default", config.lists.policy, action),
+        }
+        return 0;
+    }
+
+    let source = from.to_string();
+    match fwall.whitelist_rule(&source) {
+        Some(rule) => println!("{}: {} matched by {}", config.lists.whitelist, source, rule),
+        None => println!("{}: {} not listed, denied", config.lists.whitelist, source),
+    }
+    let mut blocked = false;
+    for name in hosts.iter().cloned().chain(addrs.iter().map(|a| a.to_string())) {
+        if let Some(rule) = fwall.blacklist_rule(&name) {
+            println!("{}: {} matched by {}", config.lists.blacklist, name, rule);
+            blocked = true;
+        }
+    }
+    if !blocked {
+        println!("{}: {} not listed", config.lists.blacklist, target);
+    }
+
+    0
+}
@@ -0,0 +1,633 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+/// Config file read when no path is given on the command line. If it doesn't
+/// exist the built in defaults are used.
+pub const DEFAULT_CONFIG_FILE: &str = "shallot.toml";
+
+/// Everything that can be set in shallot.toml. Every key is optional and
+/// defaults to the values Shallot used before it had a config file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listener: ListenerConfig,
+    pub lists: ListsConfig,
+    pub logging: LoggingConfig,
+    pub cache: CacheConfig,
+    pub buffers: BufferConfig,
+    pub limits: LimitsConfig,
+    pub timeouts: TimeoutsConfig,
+    pub block_page: BlockPageConfig,
+    pub http2: Http2Config,
+    pub socks: SocksConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Addresses to accept proxy connections on, as ip:port
+    pub addresses: Vec<String>,
+    /// What forwarded requests tell the origin about the proxy and the client
+    pub anonymity: Anonymity,
+    /// Anonymity for some of the addresses, overriding `anonymity`
+    pub anonymity_per_address: HashMap<String, Anonymity>,
+    /// Name the proxy goes by in Via headers. A request whose Via already has it has been through this proxy before.
+    pub via_pseudonym: String,
+}
+
+/// How much a forwarded request reveals. Transparent requests name the proxy in Via and the client in X-Forwarded-For
+/// and Forwarded, anonymous ones only name the proxy, and elite ones carry no sign of either.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Anonymity {
+    Transparent,
+    Anonymous,
+    Elite,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListsConfig {
+    pub whitelist: String,
+    pub blacklist: String,
+    /// Replaces the whitelist and blacklist if the file exists
+    pub policy: String,
+    /// Enables proxy authentication if the file exists
+    pub users: String,
+    pub public_suffix_list: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub event_log: String,
+    pub connection_log: String,
+    pub statistics: String,
+    /// How often the statistics file is regenerated from the event log
+    pub statistics_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// memcached server, as host:port
+    pub address: String,
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BufferConfig {
+    /// Bytes read from the client at a time while waiting for the request head
+    pub request: usize,
+    /// Largest request line and headers accepted. Bigger requests are answered with 431.
+    pub max_request_head: usize,
+    /// Size of each of the two buffers used by a CONNECT tunnel
+    pub tunnel: usize,
+    /// Move CONNECT tunnel data with splice(2) instead of through the tunnel buffers. Only used on Linux.
+    pub splice: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Connections handled at the same time. An HTTP/1.x or SOCKS connection leaves once it has become a tunnel, since
+    /// it's then counted against `tunnels` instead.
+    pub workers: usize,
+    /// Accepted connections waiting for one of those slots. Beyond this, clients are turned away with 503.
+    pub accept_queue: usize,
+    /// CONNECT, SOCKS and upgraded tunnels open at the same time, over every listener and HTTP/2 stream. Requests for
+    /// more are refused with 503.
+    pub tunnels: usize,
+    /// Value of the Retry-After header sent with 503 responses
+    pub retry_after_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    /// How long a client has to send the complete request head
+    pub request_header_secs: u64,
+    /// How long a client connection is kept open waiting for the next request once one has been answered
+    pub keep_alive_secs: u64,
+    /// How long connecting to each address of a destination may take
+    pub connect_secs: u64,
+    /// How long a CONNECT tunnel stays open without data moving in either direction
+    pub tunnel_idle_secs: u64,
+    /// How long a CONNECT tunnel stays open at most, however busy it is
+    pub tunnel_lifetime_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlockPageConfig {
+    /// Template for browsers. The built in page is used if the file doesn't exist.
+    pub html: String,
+    /// Template for clients that prefer JSON
+    pub json: String,
+    /// Link shown on the page for users who think they were blocked by mistake
+    pub contact: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http2Config {
+    /// Accept HTTP/2 from clients that start with its connection preface on the plain listeners
+    pub h2c: bool,
+    /// Streams a client may have open at once on one HTTP/2 connection
+    pub max_concurrent_streams: u32,
+    /// Addresses to accept HTTP/2 over TLS on, as ip:port
+    pub tls_addresses: Vec<String>,
+    /// PEM certificate chain presented on the TLS addresses
+    pub certificate: String,
+    /// PEM private key of the certificate
+    pub private_key: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocksConfig {
+    /// Addresses to accept SOCKS connections on, as ip:port
+    pub addresses: Vec<String>,
+    /// Accept SOCKS4 and SOCKS4a clients too. They can't authenticate, so they're refused while authentication is on.
+    pub socks4: bool,
+}
+
+impl TimeoutsConfig {
+    pub fn request_header(&self) -> Duration {
+        Duration::from_secs(self.request_header_secs)
+    }
+
+    pub fn keep_alive(&self) -> Duration {
+        Duration::from_secs(self.keep_alive_secs)
+    }
+
+    pub fn connect(&self) -> Duration {
+        Duration::from_secs(self.connect_secs)
+    }
+
+    pub fn tunnel_idle(&self) -> Duration {
+        Duration::from_secs(self.tunnel_idle_secs)
+    }
+
+    pub fn tunnel_lifetime(&self) -> Duration {
+        Duration::from_secs(self.tunnel_lifetime_secs)
+    }
+}
+
+impl Default for ListenerConfig {
+    fn default() -> ListenerConfig {
+        ListenerConfig {
+            addresses: vec!["127.0.0.1:7878".to_owned()],
+            anonymity: Anonymity::Anonymous,
+            anonymity_per_address: HashMap::new(),
+            via_pseudonym: "shallot".to_owned(),
+        }
+    }
+}
+
+impl Default for ListsConfig {
+    fn default() -> ListsConfig {
+        ListsConfig {
+            whitelist: "whitelist.txt".to_owned(),
+            blacklist: "blacklist.txt".to_owned(),
+            policy: "policy.txt".to_owned(),
+            users: "users.htpasswd".to_owned(),
+            public_suffix_list: "/usr/share/publicsuffix/public_suffix_list.dat".to_owned(),
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        LoggingConfig {
+            event_log: "event_log.txt".to_owned(),
+            connection_log: "log.txt".to_owned(),
+            statistics: "statistics.txt".to_owned(),
+            statistics_interval_secs: 5,
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            address: "127.0.0.1:11211".to_owned(),
+            timeout_secs: 5,
+        }
+    }
+}
+
+impl Default for BufferConfig {
+    fn default() -> BufferConfig {
+        BufferConfig {
+            request: 4096,
+            max_request_head: 65536,
+            tunnel: 10240,
+            splice: false,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> LimitsConfig {
+        LimitsConfig {
+            workers: 512,
+            accept_queue: 128,
+            tunnels: 1024,
+            retry_after_secs: 5,
+        }
+    }
+}
+
+impl Default for BlockPageConfig {
+    fn default() -> BlockPageConfig {
+        BlockPageConfig {
+            html: "block_page.html".to_owned(),
+            json: "block_page.json".to_owned(),
+            contact: "mailto:root@localhost".to_owned(),
+        }
+    }
+}
+
+impl Default for Http2Config {
+    fn default() -> Http2Config {
+        Http2Config {
+            h2c: true,
+            max_concurrent_streams: 100,
+            tls_addresses: vec![],
+            certificate: "shallot.crt".to_owned(),
+            private_key: "shallot.key".to_owned(),
+        }
+    }
+}
+
+impl Default for SocksConfig {
+    fn default() -> SocksConfig {
+        SocksConfig {
+            addresses: vec![],
+            socks4: true,
+        }
+    }
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> TimeoutsConfig {
+        TimeoutsConfig {
+            request_header_secs: 30,
+            keep_alive_secs: 15,
+            connect_secs: 10,
+            tunnel_idle_secs: 300,
+            tunnel_lifetime_secs: 86400,
+        }
+    }
+}
+
+/// A problem with the configuration, naming the key it was found at
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(key: &str, message: String) -> ConfigError {
+        ConfigError {
+            key: key.to_owned(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.key.is_empty() {
+            true => write!(f, "{}", self.message),
+            false => write!(f, "{}: {}", self.key, self.message),
+        }
+    }
+}
+
+impl Config {
+    /// Load and validate the configuration. An explicitly given file has to
+    /// exist, while the default shallot.toml falls back to built in defaults.
+    pub fn load(path: Option<&str>) -> Result<Config, ConfigError> {
+        let config = match path {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Self::from_file(DEFAULT_CONFIG_FILE)?,
+            None => Config::default(),
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &str) -> Result<Config, ConfigError> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| ConfigError::new("", format!("Could not read {}: {}", path, e)))?;
+        Self::parse(&content).map_err(|e| ConfigError {
+            message: format!("{}: {}", path, e.message),
+            ..e
+        })
+    }
+
+    /// Parse the contents of a config file without validating it
+    pub fn parse(content: &str) -> Result<Config, ConfigError> {
+        toml::from_str(content).map_err(|e| ConfigError::new("", e.to_string().trim_end().to_owned()))
+    }
+
+    /// Check values that parse but can't work, such as unreadable list files
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.listener.addresses.is_empty() {
+            return Err(ConfigError::new(
+                "listener.addresses",
+                "At least one address is required".to_owned(),
+            ));
+        }
+        for (i, addr) in self.listener.addresses.iter().enumerate() {
+            if addr.parse::<SocketAddr>().is_err() {
+                return Err(ConfigError::new(
+                    &format!("listener.addresses[{}]", i),
+                    format!("'{}' is not an ip:port address", addr),
+                ));
+            }
+        }
+        for (i, addr) in self.http2.tls_addresses.iter().enumerate() {
+            if addr.parse::<SocketAddr>().is_err() {
+                return Err(ConfigError::new(
+                    &format!("http2.tls_addresses[{}]", i),
+                    format!("'{}' is not an ip:port address", addr),
+                ));
+            }
+        }
+        for (i, addr) in self.socks.addresses.iter().enumerate() {
+            if addr.parse::<SocketAddr>().is_err() {
+                return Err(ConfigError::new(
+                    &format!("socks.addresses[{}]", i),
+                    format!("'{}' is not an ip:port address", addr),
+                ));
+            }
+        }
+        for addr in self.listener.anonymity_per_address.keys() {
+            if !self.listener.addresses.contains(addr) && !self.http2.tls_addresses.contains(addr) {
+                return Err(ConfigError::new(
+                    &format!("listener.anonymity_per_address.\"{}\"", addr),
+                    format!("'{}' is not one of listener.addresses or http2.tls_addresses", addr),
+                ));
+            }
+        }
+
+        let pseudonym = &self.listener.via_pseudonym;
+        if pseudonym.is_empty() || pseudonym.contains(|c: char| c.is_whitespace() || c == ',' || c == '(') {
+            return Err(ConfigError::new(
+                "listener.via_pseudonym",
+                format!("'{}' is not a single word", pseudonym),
+            ));
+        }
+
+        check_readable("lists.whitelist", &self.lists.whitelist)?;
+        check_readable("lists.blacklist", &self.lists.blacklist)?;
+
+        check_appendable("logging.event_log", &self.logging.event_log)?;
+        check_appendable("logging.connection_log", &self.logging.connection_log)?;
+        check_appendable("logging.statistics", &self.logging.statistics)?;
+        if self.logging.statistics_interval_secs == 0 {
+            return Err(ConfigError::new(
+                "logging.statistics_interval_secs",
+                "Must be at least 1".to_owned(),
+            ));
+        }
+
+        if self.cache.address.rsplit_once(':').and_then(|(_, p)| p.parse::<u16>().ok()).is_none() {
+            return Err(ConfigError::new(
+                "cache.address",
+                format!("'{}' is not a host:port address", self.cache.address),
+            ));
+        }
+        if self.cache.timeout_secs == 0 {
+            return Err(ConfigError::new("cache.timeout_secs", "Must be at least 1".to_owned()));
+        }
+
+        check_size("buffers.request", self.buffers.request)?;
+        check_size("buffers.max_request_head", self.buffers.max_request_head)?;
+        check_size("buffers.tunnel", self.buffers.tunnel)?;
+
+        if self.limits.workers == 0 {
+            return Err(ConfigError::new("limits.workers", "Must be at least 1".to_owned()));
+        }
+        if self.limits.tunnels == 0 {
+            return Err(ConfigError::new("limits.tunnels", "Must be at least 1".to_owned()));
+        }
+
+        for (key, secs) in [
+            ("timeouts.request_header_secs", self.timeouts.request_header_secs),
+            ("timeouts.keep_alive_secs", self.timeouts.keep_alive_secs),
+            ("timeouts.connect_secs", self.timeouts.connect_secs),
+            ("timeouts.tunnel_idle_secs", self.timeouts.tunnel_idle_secs),
+            ("timeouts.tunnel_lifetime_secs", self.timeouts.tunnel_lifetime_secs),
+        ] {
+            if secs == 0 {
+                return Err(ConfigError::new(key, "Must be at least 1".to_owned()));
+            }
+        }
+
+        if self.http2.max_concurrent_streams == 0 {
+            return Err(ConfigError::new(
+                "http2.max_concurrent_streams",
+                "Must be at least 1".to_owned(),
+            ));
+        }
+        if !self.http2.tls_addresses.is_empty() {
+            check_readable("http2.certificate", &self.http2.certificate)?;
+            check_readable("http2.private_key", &self.http2.private_key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Listen addresses. Only valid after validate() has passed.
+    pub fn listen_addresses(&self) -> Vec<SocketAddr> {
+        self.listener
+            .addresses
+            .iter()
+            .filter_map(|a| a.parse().ok())
+            .collect()
+    }
+
+    /// Addresses of the TLS listeners. Only valid after validate() has passed.
+    pub fn tls_addresses(&self) -> Vec<SocketAddr> {
+        self.http2
+            .tls_addresses
+            .iter()
+            .filter_map(|a| a.parse().ok())
+            .collect()
+    }
+
+    /// Addresses of the SOCKS listeners. Only valid after validate() has passed.
+    pub fn socks_addresses(&self) -> Vec<SocketAddr> {
+        self.socks
+            .addresses
+            .iter()
+            .filter_map(|a| a.parse().ok())
+            .collect()
+    }
+
+    /// Every address the proxy accepts connections on, HTTP, TLS or SOCKS
+    pub fn own_addresses(&self) -> Vec<SocketAddr> {
+        let mut addrs = self.listen_addresses();
+        addrs.extend(self.tls_addresses());
+        addrs.extend(self.socks_addresses());
+        addrs
+    }
+}
+
+impl ListenerConfig {
+    /// Anonymity of requests forwarded for clients of the given listen address
+    pub fn anonymity_of(&self, addr: &SocketAddr) -> Anonymity {
+        self.anonymity_per_address
+            .iter()
+            .find(|(a, _)| a.parse::<SocketAddr>().ok().as_ref() == Some(addr))
+            .map_or(self.anonymity, |(_, anonymity)| *anonymity)
+    }
+}
+
+fn check_readable(key: &str, path: &str) -> Result<(), ConfigError> {
+    match fs::File::open(path) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(ConfigError::new(key, format!("Cannot read '{}': {}", path, e))),
+    }
+}
+
+/// Check that a log can be written without creating it, so checking a config leaves the filesystem as it was. A log
+/// that doesn't exist yet is created on its first line, which needs a writable directory.
+fn check_appendable(key: &str, path: &str) -> Result<(), ConfigError> {
+    let cannot_write = |reason: String| ConfigError::new(key, format!("Cannot write '{}': {}", path, reason));
+
+    match OpenOptions::new().append(true).open(path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let dir = match Path::new(path).parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            match fs::metadata(dir) {
+                Ok(meta) if meta.is_dir() && !meta.permissions().readonly() => Ok(()),
+                Ok(_) => Err(cannot_write(format!("{} is not a writable directory", dir.display()))),
+                Err(e) => Err(cannot_write(e.to_string())),
+            }
+        }
+        Err(e) => Err(cannot_write(e.to_string())),
+    }
+}
+
+fn check_size(key: &str, size: usize) -> Result<(), ConfigError> {
+    match (1024..=1 << 20).contains(&size) {
+        true => Ok(()),
+        false => Err(ConfigError::new(
+            key,
+            format!("{} is outside 1024 to 1048576 bytes", size),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test_config {
+
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.listener.addresses, vec!["127.0.0.1:7878"]);
+        assert_eq!(config.lists.blacklist, "blacklist.txt");
+        assert_eq!(config.buffers.tunnel, 10240);
+
+        let config = Config::parse(
+            "[listener]\naddresses = [\"127.0.0.1:7878\", \"[::1]:3128\"]\n\
+             [listener.anonymity_per_address]\n\"[::1]:3128\" = \"elite\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.listener.anonymity_of(&"127.0.0.1:7878".parse().unwrap()), Anonymity::Anonymous);
+        assert_eq!(config.listener.anonymity_of(&"[::1]:3128".parse().unwrap()), Anonymity::Elite);
+
+        let config = Config::parse("[buffers]\nrequest = 8192\n").unwrap();
+        assert_eq!(config.buffers.request, 8192);
+        assert_eq!(config.buffers.tunnel, 10240);
+    }
+
+    #[test]
+    fn test_errors_name_key() {
+        let e = Config::parse("[listener]\nadresses = []\n").unwrap_err();
+        assert!(e.message.contains("adresses"), "{}", e);
+
+        let e = Config::parse("[buffers]\nrequest = \"big\"\n").unwrap_err();
+        assert!(e.message.contains("request"), "{}", e);
+
+        let mut config = Config::default();
+        config.listener.addresses.push("localhost".to_owned());
+        assert_eq!(config.validate().unwrap_err().key, "listener.addresses[1]");
+
+        let mut config = Config::default();
+        config.listener.anonymity_per_address.insert("127.0.0.1:3128".to_owned(), Anonymity::Elite);
+        assert_eq!(
+            config.validate().unwrap_err().key,
+            "listener.anonymity_per_address.\"127.0.0.1:3128\""
+        );
+
+        let mut config = Config::default();
+        config.lists.whitelist = "/nonexistent/whitelist.txt".to_owned();
+        assert_eq!(config.validate().unwrap_err().key, "lists.whitelist");
+
+        let mut config = Config::default();
+        config.cache.address = "localhost".to_owned();
+        assert_eq!(config.validate().unwrap_err().key, "cache.address");
+
+        let mut config = Config::default();
+        config.cache.timeout_secs = 0;
+        assert_eq!(config.validate().unwrap_err().key, "cache.timeout_secs");
+
+        let mut config = Config::default();
+        config.buffers.tunnel = 10;
+        assert_eq!(config.validate().unwrap_err().key, "buffers.tunnel");
+
+        let mut config = Config::default();
+        config.limits.workers = 0;
+        assert_eq!(config.validate().unwrap_err().key, "limits.workers");
+
+        let mut config = Config::default();
+        config.limits.tunnels = 0;
+        assert_eq!(config.validate().unwrap_err().key, "limits.tunnels");
+
+        let mut config = Config::default();
+        config.timeouts.tunnel_idle_secs = 0;
+        assert_eq!(config.validate().unwrap_err().key, "timeouts.tunnel_idle_secs");
+
+        let mut config = Config::default();
+        config.http2.tls_addresses.push("127.0.0.1:7879".to_owned());
+        config.http2.certificate = "/nonexistent/shallot.crt".to_owned();
+        assert_eq!(config.validate().unwrap_err().key, "http2.certificate");
+
+        let mut config = Config::default();
+        config.socks.addresses.push("1080".to_owned());
+        assert_eq!(config.validate().unwrap_err().key, "socks.addresses[0]");
+    }
+
+    #[test]
+    fn test_check_appendable() {
+        let path = std::env::temp_dir().join(format!("shallot_check_{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert!(check_appendable("logging.event_log", path).is_ok());
+        assert!(!Path::new(path).exists());
+
+        let e = check_appendable("logging.event_log", "/nonexistent/event_log.txt").unwrap_err();
+        assert_eq!(e.key, "logging.event_log");
+    }
+}
@@ -1,195 +1,358 @@
-use std::fs::{File};
 use std::fs;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
 use std::time::SystemTime;
-use std::io::{prelude::*, BufReader};
-
-pub enum Event {
-    WhiteListDeny,
-    BlackListDeny,
-    Connection,
-    DataTransfer,
-    ProxyServer,
-    SuspiciousActivity,
-    Uncategorized,
+
+use arc_swap::ArcSwap;
+use publicsuffix::List;
+
+use crate::auth::AuthResult;
+use crate::auth::UserStore;
+use crate::auth::parse_users;
+use crate::config::ListsConfig;
+use crate::logging;
+use crate::logging::Event;
+use crate::policy::Client;
+use crate::policy::Destination;
+use crate::policy::Policy;
+use crate::policy::Verdict;
+use crate::rules::RuleList;
+
+/// The lists, policy and users compiled at one point in time. A snapshot is never changed once it's built: when a file
+/// changes a new snapshot replaces it in the SharedFirewall, and connections holding the old one finish with it.
+#[derive(Debug, Clone)]
+pub struct Firewall {
+    blacklist: RuleList,
+    whitelist: RuleList,
+    // Used to find the registrable domain of a host for site: rules. Parsing the list is expensive, so it's loaded
+    // once and shared between snapshots.
+    psl: Option<Arc<List>>,
+    users: UserStore,
+    // Replaces the whitelist and blacklist when the policy file exists.
+    policy: Option<Policy>,
+}
+
+/// How often the files behind the shared firewall and block page are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The current firewall snapshot, shared by every connection. Taking a snapshot doesn't lock anything or touch the
+/// filesystem, so a slow connection or disk can't hold up the others. The files are watched by a task of their own.
+#[derive(Debug)]
+pub struct SharedFirewall {
+    // Paths of the list, policy and user files
+    config: ListsConfig,
+    psl: Option<Arc<List>>,
+    current: ArcSwap<Firewall>,
+    // Modification times of the files the current snapshot was compiled from. Only locked to recompile, while
+    // connections carry on with the current snapshot.
+    loaded: Mutex<FileTimes>,
 }
 
+/// Modification times of the list, policy and user files. None if the file doesn't exist or the operating system
+/// can't report modification times.
 #[derive(Debug, Clone, PartialEq)]
-pub struct Firewall {
-    blacklist: Vec<String>,
-    whitelist: Vec<String>,
-    // If the operating system can get a modified time, this will be set to true and
-    // the list files can be changed while the server is running.
-    systime_supported: bool,
-    blacklist_last_updated: SystemTime,
-    whitelist_last_updated: SystemTime,
+struct FileTimes {
+    whitelist: Option<SystemTime>,
+    blacklist: Option<SystemTime>,
+    policy: Option<SystemTime>,
+    users: Option<SystemTime>,
 }
 
-impl Firewall {
+impl FileTimes {
+    fn of(config: &ListsConfig) -> FileTimes {
+        FileTimes {
+            whitelist: modified(&config.whitelist),
+            blacklist: modified(&config.blacklist),
+            policy: modified(&config.policy),
+            users: modified(&config.users),
+        }
+    }
+}
 
-    pub fn new() -> Firewall {
-        // Initialize the blacklist and the whitelist.
-        let blacklist = Self::update_blacklist();
-        let whitelist = Self::update_whitelist();
-
-        // Check if the system supports checking file modification by attempting to obtain it.
-        let metadata = fs::metadata("blacklist.txt").unwrap();
-
-        // If this works out, the system does support it. Get the modified timestamps for both lists.
-        if let Ok(time) = metadata.modified() {
-            let systime_supported = true;
-            let blacklist_last_updated = time;
-            let metadata = fs::metadata("whitelist.txt").unwrap();
-            if let Ok(other_time) = metadata.modified() {
-                let whitelist_last_updated = other_time;
-                Firewall {
-                    blacklist,
-                    whitelist,
-                    systime_supported,
-                    blacklist_last_updated,
-                    whitelist_last_updated,
-                }
-            }
-            else {
-                // This means the check somehow got the result the first time but didn't work the second time.
-                // Default values will have to do.
-                let systime_supported = false;
-                Firewall {
-                    blacklist,
-                    whitelist,
-                    systime_supported,
-                    blacklist_last_updated: SystemTime::now(),
-                    whitelist_last_updated: SystemTime::now(),
-                }
-            }
+impl SharedFirewall {
+    pub fn new(config: &ListsConfig) -> SharedFirewall {
+        let psl = Firewall::load_public_suffix_list(&config.public_suffix_list);
+
+        SharedFirewall {
+            loaded: Mutex::new(FileTimes::of(config)),
+            current: ArcSwap::from_pointee(Firewall::compile(config, psl.clone())),
+            config: config.clone(),
+            psl,
         }
-        // If not, that does away with the possibility of editing on the fly. Dummy data will suffice.
-        else {
-            let systime_supported = false;
-            Firewall {
-                blacklist,
-                whitelist,
-                systime_supported,
-                blacklist_last_updated: SystemTime::now(),
-                whitelist_last_updated: SystemTime::now(),
-            }
+    }
+
+    /// Returns the current snapshot
+    pub fn snapshot(&self) -> Arc<Firewall> {
+        self.current.load_full()
+    }
+
+    /// Compile a new snapshot and swap it in if any of the files changed since the current one was compiled, so the
+    /// lists can be edited while the server is running. This reads files, so it's run by `watch` rather than by
+    /// connections.
+    pub fn reload(&self) {
+        let times = FileTimes::of(&self.config);
+        let mut loaded = self.loaded.lock().unwrap();
+
+        if *loaded != times {
+            self.current
+                .store(Arc::new(Firewall::compile(&self.config, self.psl.clone())));
+            *loaded = times;
+            logging::event_log(Event::ProxyServer, "Reloaded lists, policy and users");
         }
     }
+}
 
-    /// Returns true if the given ip is in the blacklist. If supported, also checks if the blacklist has changed and
-    /// updates it if necessary.
-    pub fn in_blacklist(&mut self, ip: &str) -> bool {
-        // Update the blacklist if it's been modified since the last time a request was made.
-        if self.systime_supported {
-            // Shouldn't need to check if it's OK because the flag covers that.
-            let modded = fs::metadata("blacklist.txt").unwrap().modified().unwrap();
-            if modded != self.blacklist_last_updated {
-                self.blacklist = Self::update_blacklist();
-            }
+impl Firewall {
+
+    /// Compile a snapshot of the files named in the configuration, loading the public suffix list
+    pub fn new(config: &ListsConfig) -> Firewall {
+        Self::compile(config, Self::load_public_suffix_list(&config.public_suffix_list))
+    }
+
+    fn compile(config: &ListsConfig, psl: Option<Arc<List>>) -> Firewall {
+        Firewall {
+            blacklist: Self::load_list(&config.blacklist, psl.as_deref()),
+            whitelist: Self::load_list(&config.whitelist, psl.as_deref()),
+            policy: Self::load_policy(&config.policy, psl.as_deref()),
+            users: UserStore::new(&config.users),
+            psl,
         }
+    }
 
-        Self::check_list(self, "blacklist", ip)
+    /// Returns true if the given ip or hostname is in the blacklist
+    pub fn in_blacklist(&self, target: &str) -> bool {
+        self.blacklist_rule(target).is_some()
     }
 
-    /// Returns true if the given ip is in the whitelist. If supported, also checks if the whitelist has changed and
-    /// updates it if necessary.
-    pub fn in_whitelist(&mut self, ip: &str) -> bool {
-        if self.systime_supported {
-            let modded = fs::metadata("whitelist.txt").unwrap().modified().unwrap();
-            if modded != self.whitelist_last_updated {
-                self.whitelist = Self::update_whitelist();
-            }
+    /// Returns true if the given ip or hostname is in the whitelist
+    pub fn in_whitelist(&self, target: &str) -> bool {
+        self.whitelist_rule(target).is_some()
+    }
+
+    /// Returns the blacklist rule matching the given ip or hostname, if any
+    pub fn blacklist_rule(&self, target: &str) -> Option<String> {
+        self.blacklist.find(target, self.psl.as_deref())
+    }
+
+    /// Returns the whitelist rule matching the given ip or hostname, if any
+    pub fn whitelist_rule(&self, target: &str) -> Option<String> {
+        self.whitelist.find(target, self.psl.as_deref())
+    }
+
+    /// Checks the Proxy-Authorization header of a request against the user store
+    pub async fn authenticate(&self, proxy_authorization: Option<&str>) -> AuthResult {
+        self.users.authenticate(proxy_authorization).await
+    }
+
+    /// Checks a username and password from a client that doesn't speak HTTP against the user store
+    pub async fn authenticate_user(&self, user: &str, password: &str) -> AuthResult {
+        self.users.authenticate_user(user, password).await
+    }
+
+    /// Returns true if clients have to authenticate
+    pub fn auth_enabled(&self) -> bool {
+        self.users.enabled()
+    }
+
+    /// Returns true if access is governed by the policy file instead of the whitelist and blacklist
+    pub fn policy_enabled(&self) -> bool {
+        self.policy.is_some()
+    }
+
+    /// Evaluates the policy for a request, or returns None if there is no policy file.
+    pub fn evaluate_policy(&self, client: &Client, dst: &Destination) -> Option<Verdict> {
+        self.policy
+            .as_ref()
+            .map(|policy| policy.evaluate(client, dst, self.psl.as_deref()))
+    }
+
+    fn load_policy(path: &str, psl: Option<&List>) -> Option<Policy> {
+        let content = fs::read_to_string(path).ok()?;
+        let (policy, errors) = Policy::parse(&content, psl);
+
+        for (n, e) in errors {
+            logging::event_log(
+                Event::ProxyServer,
+                &format!("Ignoring {} line {}: {}", path, n, e),
+            );
         }
 
-        Self::check_list(self, "whitelist", ip)
-    }
-
-    fn check_list(&self, list: &str, ip: &str) -> bool {
-        // Adding another function call to the stack is worse than a bit of repeated code, especially for operations
-        // which have low margin for overhead. The comparison of IPs will be copied between whitelists and blacklists.
-        let split_ip: Vec<&str> = ip.split(".").collect();
-
-        if list == "whitelist" {
-            for list_ip in self.whitelist.iter() {
-                // True until proven false for each part of the IP comparison. If still true at the end, it's a match.
-                let mut same = true;
-                let split_list_ip: Vec<&str> = list_ip.split(".").collect();
-                for i in 0..split_ip.len() {
-                    if split_ip[i] != split_list_ip[i] && split_list_ip[i] != "*" {
-                        same = false;
-                        break;
-                    }
-                }
-                // This means all pieces matched, either exactly or because of wildcards. This is a hit.
-                if same {
-                    return true;
-                }
+        Some(policy)
+    }
+
+    /// Without the public suffix list, site: rules still work but only match the domain they name and its
+    /// subdomains, so this isn't fatal.
+    fn load_public_suffix_list(path: &str) -> Option<Arc<List>> {
+        match List::from_path(path) {
+            Ok(list) => Some(Arc::new(list)),
+            Err(e) => {
+                logging::event_log(
+                    Event::ProxyServer,
+                    &format!("Could not load public suffix list {}: {}", path, e),
+                );
+                None
             }
         }
-        else if list == "blacklist" {
-            for list_ip in self.blacklist.iter() {
-                // True until proven false for each part of the IP comparison. If still true at the end, it's a match.
-                let mut same = true;
-                let split_list_ip: Vec<&str> = list_ip.split(".").collect();
-                for i in 0..split_ip.len() {
-                    if split_ip[i] != split_list_ip[i] && split_list_ip[i] != "*" {
-                        same = false;
-                        break;
-                    }
-                }
-                // This means all pieces matched, either exactly or because of wildcards. This is a hit.
-                if same {
-                    return true;
-                }
+    }
+
+    /// Reads a list file. Lines that don't parse are noted in the event log so a typo doesn't go unnoticed. A list that
+    /// can't be read at all is treated as empty.
+    fn load_list(path: &str, psl: Option<&List>) -> RuleList {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => {
+                logging::event_log(Event::ProxyServer, &format!("Could not read {}: {}", path, e));
+                return RuleList::default();
             }
+        };
+
+        let (list, errors) = parse_list(&content, psl);
+        for (n, e) in errors {
+            logging::event_log(
+                Event::ProxyServer,
+                &format!("Ignoring {} line {}: {}", path, n, e),
+            );
+        }
+
+        list
+    }
+}
+
+/// Parse one address or hostname rule per line. Blank lines and lines starting with '#' are skipped. Lines that can't
+/// be parsed are skipped too and returned alongside the list with their line number and the reason.
+pub fn parse_list(content: &str, psl: Option<&List>) -> (RuleList, Vec<(usize, String)>) {
+    let mut list = RuleList::default();
+    let mut errors = vec![];
+
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
         }
-        
-        // The item wasn't found in the given list if we make it this far.
-        false
-    }
-
-    fn update_blacklist() -> Vec<String> {
-        let mut result: Vec<String> = vec!();
-
-        let f = File::open("blacklist.txt").unwrap();
-        let r = BufReader::new(f);
-
-        for line in r.lines() {
-            match line {
-                Ok(line) => {
-                    // The line was read in, but the format has to match an IPV4.
-                    let check_fmt: Vec<&str> = line.split(".").collect();
-                    if check_fmt.len() == 4 {
-                        result.push(line);
-                    }
-                }
-                // If something went wrong with reading the line, just skip it.
-                _ => ()
-            };
+
+        if let Err(e) = list.add_line(line, psl) {
+            errors.push((n + 1, e));
         }
-        
-        result
-    }
-
-    fn update_whitelist() -> Vec<String>{
-        let mut result: Vec<String> = vec!();
-
-        let f = File::open("whitelist.txt").unwrap();
-        let r = BufReader::new(f);
-
-        for line in r.lines() {
-            match line {
-                Ok(line) => {
-                    // The line was read in, but the format has to match an IPV4.
-                    let check_fmt: Vec<&str> = line.split(".").collect();
-                    if check_fmt.len() == 4 {
-                        result.push(line);
-                    }
-                }
-                // If something went wrong with reading the line, just skip it.
-                _ => ()
-            };
+    }
+
+    (list, errors)
+}
+
+/// Parse the list, policy and user files the way the firewall would and describe every problem found, for checking a
+/// configuration without starting the server. Files that don't exist are only a problem for the whitelist and
+/// blacklist, the others are optional.
+pub fn check_files(config: &ListsConfig) -> Vec<String> {
+    let psl = List::from_path(&config.public_suffix_list).ok();
+    let mut problems = vec![];
+
+    for path in [&config.whitelist, &config.blacklist] {
+        match fs::read_to_string(path) {
+            Ok(content) => problems.extend(describe(path, parse_list(&content, psl.as_ref()).1)),
+            Err(e) => problems.push(format!("{}: {}", path, e)),
         }
-        
-        result
     }
-}
\ No newline at end of file
+    if let Ok(content) = fs::read_to_string(&config.policy) {
+        problems.extend(describe(&config.policy, Policy::parse(&content, psl.as_ref()).1));
+    }
+    if let Ok(content) = fs::read_to_string(&config.users) {
+        problems.extend(describe(&config.users, parse_users(&content).1));
+    }
+
+    problems
+}
+
+fn describe(path: &str, errors: Vec<(usize, String)>) -> impl Iterator<Item = String> + '_ {
+    errors
+        .into_iter()
+        .map(move |(n, e)| format!("{} line {}: {}", path, n, e))
+}
+
+/// Call `reload` on `shared` every RELOAD_INTERVAL for as long as the server runs. It runs on tokio's blocking threads,
+/// since it stats and reads files.
+pub async fn watch<T: Send + Sync + 'static>(shared: Arc<T>, reload: fn(&T)) {
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+        let shared = Arc::clone(&shared);
+        let _ = tokio::task::spawn_blocking(move || reload(&shared)).await;
+    }
+}
+
+/// Modification time of a file, or None if it doesn't exist or the system can't tell
+pub fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod test_firewall {
+
+    use super::*;
+
+    #[test]
+    fn test_load_list() {
+        let path = std::env::temp_dir().join("shallot_test_load_list.txt");
+        fs::write(
+            &path,
+            "# comment\n\n10.0.0.0/8\n*.facebook.com\nnot a rule\n2001:db8::/32\n",
+        )
+        .unwrap();
+
+        let list = Firewall::load_list(path.to_str().unwrap(), None);
+        fs::remove_file(&path).unwrap();
+
+        let (_, errors) = parse_list("10.0.0.1\n\nnot a rule\n", None);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 3);
+
+        assert_eq!(list.addrs.len(), 2);
+        assert_eq!(list.hosts.len(), 1);
+        assert!(list.find("10.9.9.9", None).is_some());
+        assert!(list.find("2001:db8::5", None).is_some());
+        assert!(list.find("www.facebook.com", None).is_some());
+        assert!(list.find("example.com", None).is_none());
+    }
+
+    #[test]
+    fn test_snapshot_reload() {
+        let dir = std::env::temp_dir();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_owned();
+        let config = ListsConfig {
+            whitelist: path("shallot_test_snapshot_whitelist.txt"),
+            blacklist: path("shallot_test_snapshot_blacklist.txt"),
+            policy: path("shallot_test_snapshot_missing_policy.txt"),
+            users: path("shallot_test_snapshot_missing_users"),
+            public_suffix_list: path("shallot_test_snapshot_missing_psl.dat"),
+        };
+        fs::write(&config.whitelist, "127.0.0.1\n").unwrap();
+        fs::write(&config.blacklist, "1.2.3.4\n").unwrap();
+
+        let shared = SharedFirewall::new(&config);
+        let before = shared.snapshot();
+        assert!(before.in_blacklist("1.2.3.4"));
+        assert!(!before.in_blacklist("5.6.7.8"));
+
+        // Modification times can be coarse, so make sure this one differs
+        fs::write(&config.blacklist, "5.6.7.8\n").unwrap();
+        let later = SystemTime::now() + std::time::Duration::from_secs(10);
+        fs::File::options()
+            .write(true)
+            .open(&config.blacklist)
+            .and_then(|f| f.set_modified(later))
+            .unwrap();
+
+        // Nothing changes until the files are checked
+        assert!(shared.snapshot().in_blacklist("1.2.3.4"));
+        shared.reload();
+        let after = shared.snapshot();
+        fs::remove_file(&config.whitelist).unwrap();
+        fs::remove_file(&config.blacklist).unwrap();
+
+        assert!(after.in_blacklist("5.6.7.8"));
+        assert!(!after.in_blacklist("1.2.3.4"));
+        // A snapshot taken before the reload is unaffected
+        assert!(before.in_blacklist("1.2.3.4"));
+    }
+}
@@ -0,0 +1,815 @@
+use std::future::poll_fn;
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::IpAddr;
+use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use h2::ext::Protocol;
+use h2::server::SendResponse;
+use h2::{Reason, RecvStream, SendStream};
+use http::request::Parts;
+use http::uri::Scheme;
+use http::{HeaderMap, HeaderName, HeaderValue, Method, Response, StatusCode};
+use socket2::SockRef;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time;
+use tokio::time::Instant;
+use tokio_rustls::rustls;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::TlsAcceptor;
+
+use crate::admission;
+use crate::admission::TunnelSlot;
+use crate::block_page::SharedBlockPage;
+use crate::config::Anonymity;
+use crate::config::Config;
+use crate::config::Http2Config;
+use crate::firewall::SharedFirewall;
+use crate::http_message;
+use crate::http_message::BodyFraming;
+use crate::http_message::BodyReader;
+use crate::http_message::Header;
+use crate::http_message::HttpRequest;
+use crate::logging;
+use crate::logging::Event;
+use crate::proxy_listener::ProxyError;
+use crate::proxy_listener::Result;
+use crate::request_handler;
+use crate::request_handler::ErrorResponse;
+
+/// First bytes a client sends on an HTTP/2 connection (RFC 9113 section 3.4)
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Headers HTTP/2 doesn't allow, since it manages connections itself (RFC 9113 section 8.2.2)
+const CONNECTION_SPECIFIC: [&str; 6] = [
+    "Connection",
+    "Proxy-Connection",
+    "Keep-Alive",
+    "Transfer-Encoding",
+    "Upgrade",
+    "TE",
+];
+
+/// What the streams of one HTTP/2 connection share
+struct Connection {
+    firewall: Arc<SharedFirewall>,
+    block_page: Arc<SharedBlockPage>,
+    config: Arc<Config>,
+    anonymity: Anonymity,
+    src_addr: IpAddr,
+    local_ip: IpAddr,
+}
+
+/// Whether a client connection starts with the HTTP/2 preface, without taking anything off the connection. Waits until
+/// enough of it has arrived to tell, failing with RequestTimeout if that takes longer than `timeout`.
+pub async fn has_preface(stream: &TcpStream, timeout: Duration) -> Result<bool> {
+    let mut buf = [MaybeUninit::<u8>::uninit(); PREFACE.len()];
+
+    let peek = async {
+        let mut seen = 0;
+        loop {
+            if stream.readable().await.is_err() {
+                return false;
+            }
+            // Peeking leaves the data where it is, so the socket stays readable. When nothing new has arrived since the
+            // last peek, report WouldBlock so tokio clears that readiness and readable() waits for more data instead of
+            // returning at once. tokio keeps the readiness if more data arrived in the meantime.
+            let peeked = stream.try_io(Interest::READABLE, || match SockRef::from(stream).peek(&mut buf)? {
+                n if n > 0 && n == seen => Err(io::ErrorKind::WouldBlock.into()),
+                n => Ok(n),
+            });
+            let n = match peeked {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(_) => return false,
+            };
+
+            // SAFETY: peek initialized the first n bytes of buf
+            let peeked = unsafe { slice::from_raw_parts(buf.as_ptr().cast::<u8>(), n) };
+            if n == 0 || peeked != &PREFACE[..n] {
+                return false;
+            }
+            if n == PREFACE.len() {
+                return true;
+            }
+            seen = n;
+        }
+    };
+
+    time::timeout(timeout, peek).await.map_err(|_| ProxyError::RequestTimeout)
+}
+
+/// TLS settings of the HTTP/2 listeners. Only h2 is offered in ALPN, so clients that want HTTP/1.1 fail the handshake
+/// rather than send requests the listener wouldn't understand.
+pub fn tls_acceptor(config: &Http2Config) -> Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(&config.certificate)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| ProxyError::IO(format!("Could not load {}: {}", config.certificate, e)))?;
+    let key = PrivateKeyDer::from_pem_file(&config.private_key)
+        .map_err(|e| ProxyError::IO(format!("Could not load {}: {}", config.private_key, e)))?;
+
+    let mut server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| ProxyError::IO(format!("Invalid certificate or key: {}", e)))?;
+    server_config.alpn_protocols = vec![b"h2".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Complete the TLS handshake of a client connection on a TLS listener and serve HTTP/2 over it
+pub async fn serve_tls(
+    stream: TcpStream,
+    acceptor: TlsAcceptor,
+    firewall: &Arc<SharedFirewall>,
+    block_page: &Arc<SharedBlockPage>,
+    config: &Arc<Config>,
+    anonymity: Anonymity,
+) -> Result<()> {
+    let src_addr = stream
+        .peer_addr()
+        .map_err(|e| ProxyError::IO(format!("While getting the socket address {:?}", e)))?
+        .ip();
+    let local_ip = stream
+        .local_addr()
+        .map_err(|e| ProxyError::IO(format!("While getting the socket address {:?}", e)))?
+        .ip();
+
+    let timeout = config.timeouts.request_header();
+    let tls_stream = match time::timeout(timeout, acceptor.accept(stream)).await {
+        Ok(Ok(tls_stream)) => tls_stream,
+        Ok(Err(e)) => return Err(ProxyError::IO(format!("TLS handshake failed: {}", e))),
+        Err(_) => {
+            logging::event_log(
+                Event::Timeout,
+                &format!("No TLS handshake from {} within {}s", src_addr, timeout.as_secs()),
+            );
+            return Err(ProxyError::RequestTimeout);
+        }
+    };
+
+    serve_connection(tls_stream, firewall, block_page, config, anonymity, src_addr, local_ip).await
+}
+
+/// Serve an HTTP/2 client connection. Each stream is handled in a task of its own and goes through the same checks and
+/// logging as an HTTP/1.1 request. The client is told how many streams it may have open at once, and streams opened
+/// beyond that are refused. A connection without open streams is closed after the keep-alive timeout.
+pub async fn serve_connection<T: AsyncRead + AsyncWrite + Unpin>(
+    io: T,
+    firewall: &Arc<SharedFirewall>,
+    block_page: &Arc<SharedBlockPage>,
+    config: &Arc<Config>,
+    anonymity: Anonymity,
+    src_addr: IpAddr,
+    local_ip: IpAddr,
+) -> Result<()> {
+    let timeout = config.timeouts.request_header();
+    let handshake = h2::server::Builder::new()
+        .max_concurrent_streams(config.http2.max_concurrent_streams)
+        .enable_connect_protocol()
+        .handshake::<_, Bytes>(io);
+    let mut connection = match time::timeout(timeout, handshake).await {
+        Ok(Ok(connection)) => connection,
+        Ok(Err(e)) => return Err(ProxyError::Parse(format!("Invalid HTTP/2 connection preface: {}", e))),
+        Err(_) => {
+            logging::event_log(
+                Event::Timeout,
+                &format!("No HTTP/2 preface from {} within {}s", src_addr, timeout.as_secs()),
+            );
+            return Err(ProxyError::RequestTimeout);
+        }
+    };
+
+    logging::event_log(
+        Event::Connection,
+        &format!("HTTP/2 connection from {}", src_addr),
+    );
+
+    let shared = Arc::new(Connection {
+        firewall: Arc::clone(firewall),
+        block_page: Arc::clone(block_page),
+        config: Arc::clone(config),
+        anonymity,
+        src_addr,
+        local_ip,
+    });
+    let mut tasks = JoinSet::new();
+    let mut streams = 0;
+    let mut closing = false;
+
+    // Accepting streams also drives the connection, so it goes on until the connection is closed, including while
+    // shutting down
+    let result = loop {
+        let accepted = tokio::select! {
+            accepted = connection.accept() => accepted,
+            Some(_) = tasks.join_next() => continue,
+            _ = time::sleep(config.timeouts.keep_alive()), if tasks.is_empty() && !closing => {
+                connection.graceful_shutdown();
+                closing = true;
+                continue;
+            }
+        };
+
+        match accepted {
+            Some(Ok((request, respond))) => {
+                streams += 1;
+                tasks.spawn(logging::user_scope(handle_stream(Arc::clone(&shared), request, respond)));
+            }
+            Some(Err(e)) if e.is_io() || e.is_go_away() => break Ok(()),
+            Some(Err(e)) => break Err(h2_error(e)),
+            None => break Ok(()),
+        }
+    };
+
+    logging::event_log(
+        Event::Connection,
+        &format!("HTTP/2 connection from {} closed after {} streams", src_addr, streams),
+    );
+    result
+}
+
+/// Handle one stream of an HTTP/2 connection. CONNECT opens a tunnel like it does over HTTP/1.1, CONNECT with a
+/// :protocol (RFC 8441) upgrades a request to the origin to that protocol and tunnels it, and any other method is
+/// forwarded to the origin as HTTP/1.1.
+async fn handle_stream(conn: Arc<Connection>, request: http::Request<RecvStream>, mut respond: SendResponse<Bytes>) {
+    let stream_id = respond.stream_id().as_u32();
+    let (parts, body) = request.into_parts();
+    let protocol = parts.extensions.get::<Protocol>().map(|p| p.as_str().to_ascii_lowercase());
+
+    let result = match (parts.method == Method::CONNECT, protocol) {
+        (true, None) => connect(&conn, &parts, body, &mut respond).await,
+        // Origins are only spoken to in plain HTTP/1.1, so there's no way to upgrade a request to one over TLS (wss)
+        (true, Some(protocol)) if parts.uri.scheme() == Some(&Scheme::HTTPS) => Err(reply_error(
+            &mut respond,
+            ProxyError::NotImplemented(format!("{} to https origins is not supported", protocol)),
+        )),
+        (true, Some(protocol)) => forward(&conn, &parts, body, &mut respond, Some(protocol)).await,
+        (false, _) => forward(&conn, &parts, body, &mut respond, None).await,
+    };
+
+    if let Err(e) = result {
+        logging::event_log(
+            Event::Connection,
+            &format!("Got error on HTTP/2 stream {} from {}: {:?}", stream_id, conn.src_addr, e),
+        );
+    }
+}
+
+/// Open a tunnel to the destination of a CONNECT stream
+async fn connect(conn: &Connection, parts: &Parts, body: RecvStream, respond: &mut SendResponse<Bytes>) -> Result<()> {
+    let authority = parts.uri.authority().map(|a| a.to_string()).unwrap_or_default();
+    logging::event_log(
+        Event::Connection,
+        &format!("CONNECT request for {} from {} over HTTP/2", authority, conn.src_addr),
+    );
+
+    let _slot = match admission::tunnel_slot(&conn.src_addr) {
+        Ok(slot) => slot,
+        Err(e) => return Err(reply_error(respond, e)),
+    };
+    let fwall = conn.firewall.snapshot();
+    let headers = request_headers(&parts.headers);
+    let mut t_stream = match request_handler::connect_tunnel_target(
+        &fwall,
+        &conn.config,
+        conn.src_addr,
+        conn.local_ip,
+        &authority,
+        &headers,
+    )
+    .await
+    {
+        Ok(t_stream) => t_stream,
+        Err(e) => return Err(reply_error(respond, e)),
+    };
+    let dst_addr = request_handler::peer_ip(&t_stream);
+
+    logging::event_log(
+        Event::ProxyServer,
+        &format!("{} and {} verified", conn.src_addr, dst_addr),
+    );
+
+    let send = respond.send_response(Response::new(()), false).map_err(h2_error)?;
+
+    logging::event_log(
+        Event::Connection,
+        &format!(
+            "CONNECT tunnel established between {} and {}",
+            conn.src_addr, dst_addr
+        ),
+    );
+
+    let (n, result) = tunnel(body, send, &mut t_stream, conn).await;
+    request_handler::log_tunnel_total(n, conn.src_addr, dst_addr, None);
+    result
+}
+
+/// Forward the request of a stream to its origin as HTTP/1.1 and relay the response. With `protocol`, the stream is an
+/// extended CONNECT: the origin is asked to upgrade a GET to that protocol, and once it has agreed the stream carries
+/// the new protocol in both directions.
+async fn forward(
+    conn: &Connection,
+    parts: &Parts,
+    mut body: RecvStream,
+    respond: &mut SendResponse<Bytes>,
+    protocol: Option<String>,
+) -> Result<()> {
+    let config = &conn.config;
+    let src_addr = conn.src_addr;
+
+    let req = match origin_request(parts, &body, protocol.as_deref()) {
+        Ok(req) => req,
+        Err(e) => return Err(reply_error(respond, e)),
+    };
+    logging::event_log(
+        Event::Connection,
+        &format!("{} for {} from {} over HTTP/2", req.method, req.target, src_addr),
+    );
+
+    // An extended CONNECT becomes a tunnel, so it needs room for one
+    let _slot = match req.upgrades().is_empty() {
+        true => TunnelSlot::default(),
+        false => match admission::tunnel_slot(&src_addr) {
+            Ok(slot) => slot,
+            Err(e) => return Err(reply_error(respond, e)),
+        },
+    };
+    let fwall = conn.firewall.snapshot();
+    let (mut t_stream, host, port, path) =
+        match request_handler::connect_origin(&fwall, config, src_addr, conn.local_ip, &req).await {
+            Ok(connected) => connected,
+            Err(e) => return Err(reply_block_page(conn, respond, &req, e)),
+        };
+    let dst_addr = request_handler::peer_ip(&t_stream);
+
+    logging::event_log(
+        Event::ProxyServer,
+        &format!("{} and {} verified", src_addr, dst_addr),
+    );
+
+    let pseudonym = &config.listener.via_pseudonym;
+    let head = request_handler::build_origin_request(&req, &path, &host, port, src_addr, conn.anonymity, pseudonym);
+    let (target_r, mut target_w) = t_stream.split();
+
+    target_w
+        .write_all(&head)
+        .await
+        .map_err(|e| ProxyError::IO(format!("While writing {:?}", e)))?;
+    let mut total = head.len() as u64;
+    if req.framing != BodyFraming::NoBody {
+        total += send_body(&mut body, &mut target_w, req.framing).await?;
+    }
+
+    // Interim responses aren't relayed, HTTP/2 clients don't need them to go on
+    let mut reader = BufReader::new(target_r);
+    let resp = loop {
+        let resp = match request_handler::read_response(&mut reader).await {
+            Ok(resp) => resp,
+            Err(e @ ProxyError::InvalidResponse(_)) => return Err(reply_error(respond, e)),
+            Err(e) => return Err(e),
+        };
+        total += resp.raw.len() as u64;
+        if resp.status == 101 || !(100..200).contains(&resp.status) {
+            break resp;
+        }
+    };
+
+    logging::event_log(
+        Event::Connection,
+        &format!(
+            "{} for {} from {} answered with {}",
+            req.method, req.target, src_addr, resp.status
+        ),
+    );
+
+    let headers = request_handler::relayed_response_headers(&resp, conn.anonymity, pseudonym);
+
+    if resp.status == 101 {
+        let protocol = match protocol {
+            Some(protocol) => protocol,
+            None => {
+                let e = ProxyError::InvalidResponse("Switching protocols without being asked to".to_owned());
+                return Err(reply_error(respond, e));
+            }
+        };
+
+        // The key exchange was between the proxy and the origin, the client didn't take part in it
+        let headers: Vec<Header> = headers
+            .into_iter()
+            .filter(|h| !h.name.eq_ignore_ascii_case("Sec-WebSocket-Accept"))
+            .collect();
+        let mut send = respond.send_response(response(200, &headers), false).map_err(h2_error)?;
+
+        logging::event_log(
+            Event::Connection,
+            &format!(
+                "Connection between {} and {} upgraded to {}",
+                src_addr, dst_addr, protocol
+            ),
+        );
+
+        // Whatever the origin sent after the 101 is already in the new protocol
+        let switched = reader.buffer().to_vec();
+        drop(reader);
+        send_all(&mut send, Bytes::from(switched.clone())).await?;
+
+        let (n, result) = tunnel(body, send, &mut t_stream, conn).await;
+        let total = total as usize + switched.len() + n;
+        request_handler::log_tunnel_total(total, src_addr, dst_addr, Some(&protocol));
+        return result;
+    }
+
+    let framing = match http_message::response_framing(&req.method, &resp) {
+        Ok(framing) => framing,
+        Err(e) => return Err(reply_error(respond, ProxyError::InvalidResponse(format!("{:?}", e)))),
+    };
+    let end_of_stream = framing == BodyFraming::NoBody;
+    let mut send = respond
+        .send_response(response(resp.status, &headers), end_of_stream)
+        .map_err(h2_error)?;
+
+    if !end_of_stream {
+        let mut body_reader = BodyReader::new(framing);
+        while let Some(data) = body_reader.next(&mut reader, config.buffers.tunnel).await? {
+            total += data.len() as u64;
+            send_all(&mut send, Bytes::from(data)).await?;
+        }
+        send.send_data(Bytes::new(), true).map_err(h2_error)?;
+    }
+
+    logging::event_log(
+        Event::DataTransfer,
+        &format!(
+            "Total {} bytes exchanged between {} and {}",
+            total, src_addr, dst_addr
+        ),
+    );
+
+    Ok(())
+}
+
+/// The HTTP/1.1 request for the origin equivalent to an HTTP/2 one. The authority becomes the Host header, and a body
+/// of unknown length is sent chunked. An extended CONNECT becomes a GET asking to upgrade to its protocol, and its
+/// stream is left for the tunnel.
+fn origin_request(parts: &Parts, body: &RecvStream, protocol: Option<&str>) -> Result<HttpRequest> {
+    let mut headers = request_headers(&parts.headers);
+    if http_message::find_header(&headers, "Host").is_none() {
+        if let Some(authority) = parts.uri.authority() {
+            headers.insert(0, Header::new("Host", authority.as_str().as_bytes()));
+        }
+    }
+
+    let (method, framing) = match protocol {
+        Some(protocol) => {
+            headers.push(Header::new("Connection", b"Upgrade"));
+            headers.push(Header::new("Upgrade", protocol.as_bytes()));
+            if protocol == "websocket" {
+                headers.push(Header::new("Sec-WebSocket-Key", websocket_key().as_bytes()));
+            }
+            ("GET".to_owned(), BodyFraming::NoBody)
+        }
+        None if body.is_end_stream() => (parts.method.to_string(), BodyFraming::NoBody),
+        None => match http_message::request_framing(&headers)? {
+            BodyFraming::NoBody => {
+                headers.push(Header::new("Transfer-Encoding", b"chunked"));
+                (parts.method.to_string(), BodyFraming::Chunked)
+            }
+            framing => (parts.method.to_string(), framing),
+        },
+    };
+
+    Ok(HttpRequest {
+        method,
+        target: parts.uri.to_string(),
+        version: 2,
+        headers,
+        framing,
+    })
+}
+
+/// Headers of an HTTP/2 request in the form the rest of the proxy works with. HTTP/2 clients may send each cookie in a
+/// header of its own, which go back into a single one for HTTP/1.1 (RFC 9113 section 8.2.3).
+fn request_headers(map: &HeaderMap) -> Vec<Header> {
+    let mut headers = vec![];
+    let mut cookies: Vec<&[u8]> = vec![];
+
+    for (name, value) in map {
+        match name == http::header::COOKIE {
+            true => cookies.push(value.as_bytes()),
+            false => headers.push(Header::new(name.as_str(), value.as_bytes())),
+        }
+    }
+    if !cookies.is_empty() {
+        headers.push(Header::new("cookie", &cookies.join(&b"; "[..])));
+    }
+
+    headers
+}
+
+/// An HTTP/2 response with the given status and headers. Headers HTTP/2 doesn't allow or can't carry are left out.
+fn response(status: u16, headers: &[Header]) -> Response<()> {
+    let mut response = Response::new(());
+    *response.status_mut() = StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY);
+
+    for h in headers {
+        if CONNECTION_SPECIFIC.iter().any(|name| name.eq_ignore_ascii_case(&h.name)) {
+            continue;
+        }
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(h.name.as_bytes()), HeaderValue::from_bytes(&h.value)) {
+            response.headers_mut().append(name, value);
+        }
+    }
+
+    response
+}
+
+/// A nonce for the Sec-WebSocket-Key of an upgrade the proxy asks for on behalf of an HTTP/2 client. It only has to be
+/// different every time (RFC 6455 section 4.1), nothing depends on it being unpredictable.
+fn websocket_key() -> String {
+    static UPGRADES: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    let n = UPGRADES.fetch_add(1, Ordering::Relaxed);
+
+    let mut nonce = [0u8; 16];
+    nonce[..8].copy_from_slice(&nanos.to_be_bytes());
+    nonce[8..].copy_from_slice(&n.to_be_bytes());
+    STANDARD.encode(nonce)
+}
+
+/// Answer a stream with the response for an error, or reset it if the error has none, and hand the error back to be
+/// returned
+fn reply_error(respond: &mut SendResponse<Bytes>, e: ProxyError) -> ProxyError {
+    match ErrorResponse::new(&e) {
+        Some(response) => send_error_response(respond, response),
+        None => respond.send_reset(Reason::CANCEL),
+    }
+    e
+}
+
+/// Answer a stream denied by the firewall with the block page, and hand the error back to be returned
+fn reply_block_page(conn: &Connection, respond: &mut SendResponse<Bytes>, req: &HttpRequest, e: ProxyError) -> ProxyError {
+    let accept = http_message::find_header(&req.headers, "Accept").map(|h| h.value_str());
+
+    match ErrorResponse::block_page(&conn.block_page, &req.target, accept, &e) {
+        Some(response) => send_error_response(respond, response),
+        None => respond.send_reset(Reason::CANCEL),
+    }
+    e
+}
+
+fn send_error_response(respond: &mut SendResponse<Bytes>, error: ErrorResponse) {
+    let mut headers = error.headers;
+    headers.push(Header::new("Content-Type", error.content_type.as_bytes()));
+    headers.push(Header::new("Content-Length", error.body.len().to_string().as_bytes()));
+
+    if let Ok(mut send) = respond.send_response(response(error.reply.status, &headers), false) {
+        let _ = send.send_data(Bytes::from(error.body), true);
+    }
+}
+
+/// Pass the body of a request on to the origin in the framing of the forwarded request. Data is only acknowledged to
+/// the client, letting it send more, once it has been written. Returns the number of bytes written.
+async fn send_body<W: AsyncWrite + Unpin>(body: &mut RecvStream, target: &mut W, framing: BodyFraming) -> Result<u64> {
+    let mut total = 0u64;
+
+    while let Some(data) = body.data().await {
+        let data = data.map_err(h2_error)?;
+        if data.is_empty() {
+            continue;
+        }
+
+        let mut out = Vec::with_capacity(data.len() + 12);
+        match framing {
+            BodyFraming::Chunked => {
+                out.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+                out.extend_from_slice(&data);
+                out.extend_from_slice(b"\r\n");
+            }
+            _ => out.extend_from_slice(&data),
+        }
+        write_target(target, &out).await?;
+        total += out.len() as u64;
+        let _ = body.flow_control().release_capacity(data.len());
+    }
+
+    if framing == BodyFraming::Chunked {
+        write_target(target, b"0\r\n\r\n").await?;
+        total += 5;
+    }
+
+    Ok(total)
+}
+
+/// Send data on a stream once the client's flow control window has room for it, so a client that doesn't read holds
+/// up the sender instead of filling the proxy's memory
+async fn send_all(send: &mut SendStream<Bytes>, mut data: Bytes) -> Result<()> {
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        match poll_fn(|cx| send.poll_capacity(cx)).await {
+            Some(Ok(0)) => {}
+            Some(Ok(n)) => {
+                let chunk = data.split_to(std::cmp::min(n, data.len()));
+                send.send_data(chunk, false).map_err(h2_error)?;
+            }
+            Some(Err(e)) => return Err(h2_error(e)),
+            None => return Err(ProxyError::StreamClosed),
+        }
+    }
+
+    Ok(())
+}
+
+/// Forward data between a stream and a connection to its destination until both directions have finished, with the
+/// same idle and lifetime limits as an HTTP/1.1 tunnel. The end of the stream is passed on as a shutdown of the
+/// connection's write half and the other way around, and any error resets the stream. Returns how many bytes were moved
+/// along with how the tunnel ended, like request_handler::tunnel.
+async fn tunnel(
+    mut recv: RecvStream,
+    mut send: SendStream<Bytes>,
+    t_stream: &mut TcpStream,
+    conn: &Connection,
+) -> (usize, Result<()>) {
+    let config = &conn.config;
+    let timeouts = &config.timeouts;
+    let dst_addr = request_handler::peer_ip(t_stream);
+    let (mut target_r, mut target_w) = t_stream.split();
+    let mut buf = vec![0u8; config.buffers.tunnel];
+    let mut total_bytes = 0usize;
+    let mut client_open = true;
+    let mut target_open = true;
+    let deadline = Instant::now() + timeouts.tunnel_lifetime();
+
+    let result = loop {
+        if !(client_open || target_open) {
+            break Ok(());
+        }
+
+        tokio::select! {
+            data = recv.data(), if client_open => match data {
+                Some(Ok(data)) => {
+                    if let Err(e) = write_target(&mut target_w, &data).await {
+                        break Err(e);
+                    }
+                    let _ = recv.flow_control().release_capacity(data.len());
+                    total_bytes += data.len();
+                }
+                Some(Err(e)) => break Err(h2_error(e)),
+                None => {
+                    client_open = false;
+                    let _ = target_w.shutdown().await;
+                }
+            },
+            n = target_r.read(&mut buf), if target_open => match n {
+                Ok(0) => {
+                    target_open = false;
+                    if let Err(e) = send.send_data(Bytes::new(), true) {
+                        break Err(h2_error(e));
+                    }
+                }
+                Ok(n) => {
+                    if let Err(e) = send_all(&mut send, Bytes::copy_from_slice(&buf[..n])).await {
+                        break Err(e);
+                    }
+                    total_bytes += n;
+                }
+                Err(e) => break Err(ProxyError::IO(format!("While reading {:?}", e))),
+            },
+            _ = time::sleep(timeouts.tunnel_idle()) => break Err(ProxyError::TunnelIdleTimeout),
+            _ = time::sleep_until(deadline) => break Err(ProxyError::TunnelLifetimeExceeded),
+        }
+    };
+
+    request_handler::log_expired_tunnel(&result, timeouts, &conn.src_addr.to_string(), &dst_addr, total_bytes);
+    if result.is_err() {
+        send.send_reset(Reason::CANCEL);
+    }
+    let _ = t_stream.shutdown().await;
+
+    (total_bytes, result)
+}
+
+async fn write_target<W: AsyncWrite + Unpin>(target: &mut W, buf: &[u8]) -> Result<()> {
+    target
+        .write_all(buf)
+        .await
+        .map_err(|e| ProxyError::IO(format!("While writing {:?}", e)))
+}
+
+fn h2_error(e: h2::Error) -> ProxyError {
+    match e.reason() {
+        Some(reason) => ProxyError::IO(format!("HTTP/2 stream error {:?}", reason)),
+        None => ProxyError::IO(format!("HTTP/2 {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod test_http2 {
+
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_has_preface() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        for (sent, expected) in [(PREFACE, true), (&b"GET http://example.com/ HTTP/1.1\r\n\r\n"[..], false)] {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            client.write_all(sent).await.unwrap();
+
+            assert_eq!(has_preface(&server, Duration::from_secs(1)).await.unwrap(), expected);
+            // Nothing was taken off the connection
+            let mut buf = vec![0u8; sent.len()];
+            server.peek(&mut buf).await.unwrap();
+            assert_eq!(buf, sent);
+        }
+
+        // The rest of a preface that arrives in pieces is waited for
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        client.write_all(&PREFACE[..10]).await.unwrap();
+        let rest = tokio::spawn(async move {
+            time::sleep(Duration::from_millis(50)).await;
+            client.write_all(&PREFACE[10..]).await.unwrap();
+            client
+        });
+        assert!(has_preface(&server, Duration::from_secs(1)).await.unwrap());
+        rest.await.unwrap();
+
+        // A client that stops partway through the preface doesn't hold the connection forever
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        client.write_all(&PREFACE[..10]).await.unwrap();
+        assert!(matches!(
+            has_preface(&server, Duration::from_millis(100)).await,
+            Err(ProxyError::RequestTimeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_extended_connect_https() {
+        let config = Arc::new(Config::default());
+        let firewall = Arc::new(SharedFirewall::new(&config.lists));
+        let block_page = Arc::new(SharedBlockPage::new(&config.block_page));
+        let (client_io, server_io) = tokio::io::duplex(65536);
+        let localhost = IpAddr::from([127, 0, 0, 1]);
+        tokio::spawn(async move {
+            serve_connection(server_io, &firewall, &block_page, &config, Anonymity::Elite, localhost, localhost).await
+        });
+
+        let (client, connection) = h2::client::handshake(client_io).await.unwrap();
+        tokio::spawn(connection);
+        let mut client = client.ready().await.unwrap();
+        let mut request = http::Request::builder()
+            .method(Method::CONNECT)
+            .uri("https://127.0.0.1:8443/chat")
+            .body(())
+            .unwrap();
+        request.extensions_mut().insert(Protocol::from_static("websocket"));
+
+        // A wss request is turned down before anything is connected to
+        let (response, _) = client.send_request(request, true).unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(
+            response.headers()["proxy-status"],
+            "shallot; error=http_request_error; details=\"websocket to https origins is not supported\""
+        );
+    }
+
+    #[test]
+    fn test_headers() {
+        let mut map = HeaderMap::new();
+        map.append("cookie", HeaderValue::from_static("a=1"));
+        map.append("accept", HeaderValue::from_static("*/*"));
+        map.append("cookie", HeaderValue::from_static("b=2"));
+        let headers = request_headers(&map);
+        assert_eq!(headers[0], Header::new("accept", b"*/*"));
+        assert_eq!(headers[1], Header::new("cookie", b"a=1; b=2"));
+
+        let headers = vec![
+            Header::new("Content-Type", b"text/plain"),
+            Header::new("Transfer-Encoding", b"chunked"),
+            Header::new("Keep-Alive", b"timeout=5"),
+        ];
+        let response = response(204, &headers);
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers().len(), 1);
+        assert_eq!(response.headers()["content-type"], "text/plain");
+    }
+}
@@ -0,0 +1,597 @@
+use httparse::{Response, EMPTY_HEADER};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::proxy_listener::ProxyError;
+use crate::proxy_listener::Result;
+
+/// Upper bound on the size of a response head read from an origin server
+const MAX_HEAD_SIZE: usize = 65536;
+
+/// Size of the intermediate buffer used while relaying message bodies
+const RELAY_BUF_SIZE: usize = 10240;
+
+/// A single HTTP header. Values are kept as raw bytes since HTTP does not
+/// guarantee that they are valid UTF-8.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+impl Header {
+    pub fn new(name: &str, value: &[u8]) -> Header {
+        Header {
+            name: name.to_owned(),
+            value: value.to_vec(),
+        }
+    }
+
+    /// Header value as a string, or an empty string if it is not valid UTF-8
+    pub fn value_str(&self) -> &str {
+        std::str::from_utf8(&self.value).unwrap_or("")
+    }
+}
+
+/// Copy parsed httparse headers into owned headers
+pub fn owned_headers(headers: &[httparse::Header]) -> Vec<Header> {
+    headers
+        .iter()
+        .map(|h| Header::new(h.name, h.value))
+        .collect()
+}
+
+/// Find the first header with the given (case insensitive) name
+pub fn find_header<'a>(headers: &'a [Header], name: &str) -> Option<&'a Header> {
+    headers.iter().find(|h| h.name.eq_ignore_ascii_case(name))
+}
+
+/// Comma separated tokens of every header with the given name, lower cased. Used for list valued headers such as
+/// Connection.
+pub fn header_tokens(headers: &[Header], name: &str) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case(name))
+        .flat_map(|h| h.value_str().split(','))
+        .map(|t| t.trim().to_ascii_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+/// Serialize headers in wire format, each terminated by CRLF
+pub fn write_headers(out: &mut Vec<u8>, headers: &[Header]) {
+    for h in headers {
+        out.extend_from_slice(h.name.as_bytes());
+        out.extend_from_slice(b": ");
+        out.extend_from_slice(&h.value);
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+/// How the length of a message body is determined (RFC 9112 section 6.3)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyFraming {
+    NoBody,
+    ContentLength(u64),
+    Chunked,
+    UntilClose,
+}
+
+/// A request to be forwarded to an origin server. The body, if any, is not
+/// part of the request and is streamed separately according to `framing`.
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    /// Request target as sent by the client, in absolute-form for a proxy
+    pub target: String,
+    /// Minor version of HTTP/1.x, or 2 for a request that came in over HTTP/2
+    pub version: u8,
+    pub headers: Vec<Header>,
+    pub framing: BodyFraming,
+}
+
+impl HttpRequest {
+    /// The HTTP version the request came in with, as named in Via
+    pub fn protocol(&self) -> String {
+        match self.version {
+            2 => "2".to_owned(),
+            minor => format!("1.{}", minor),
+        }
+    }
+
+    /// True if the client asked for a 100 Continue before sending the body
+    pub fn expects_continue(&self) -> bool {
+        match find_header(&self.headers, "Expect") {
+            Some(h) => h.value_str().trim().eq_ignore_ascii_case("100-continue"),
+            None => false,
+        }
+    }
+
+    /// True if the client wants to send more requests on the same connection. HTTP/1.1 connections persist unless the
+    /// client says close, HTTP/1.0 ones only if it asks for keep-alive. Older clients send Proxy-Connection instead of
+    /// Connection to proxies, so it is honoured when there is no Connection header.
+    pub fn keep_alive(&self) -> bool {
+        let mut options = header_tokens(&self.headers, "Connection");
+        if options.is_empty() {
+            options = header_tokens(&self.headers, "Proxy-Connection");
+        }
+
+        match options.iter().any(|o| o == "close") {
+            true => false,
+            false => self.version >= 1 || options.iter().any(|o| o == "keep-alive"),
+        }
+    }
+
+    /// Protocols the client asks to switch the connection to, without their versions. Upgrade only counts when
+    /// Connection names it, as a proxy that didn't understand it would have dropped it otherwise.
+    pub fn upgrades(&self) -> Vec<String> {
+        if !header_tokens(&self.headers, "Connection").iter().any(|o| o == "upgrade") {
+            return vec![];
+        }
+
+        header_tokens(&self.headers, "Upgrade")
+            .iter()
+            .map(|p| p.split('/').next().unwrap_or("").trim().to_owned())
+            .collect()
+    }
+}
+
+/// Parsed status line and headers of a response from the origin
+#[derive(Debug)]
+pub struct ResponseHead {
+    pub status: u16,
+    pub headers: Vec<Header>,
+    /// The head exactly as it was received, including the final empty line
+    pub raw: Vec<u8>,
+}
+
+/// Read a message head (start line and headers) up to and including the
+/// empty line that terminates it.
+pub async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut head = Vec::new();
+
+    loop {
+        let start = head.len();
+        let n = reader
+            .read_until(b'\n', &mut head)
+            .await
+            .map_err(|e| ProxyError::IO(format!("While reading head {:?}", e)))?;
+
+        if n == 0 {
+            return Err(ProxyError::StreamClosed);
+        }
+        if head.len() > MAX_HEAD_SIZE {
+            return Err(ProxyError::Parse("Message head too large".to_owned()));
+        }
+
+        let line = &head[start..];
+        if line == b"\r\n" || line == b"\n" {
+            return Ok(head);
+        }
+    }
+}
+
+/// Parse a response head read with read_head
+pub fn parse_response(raw: Vec<u8>) -> Result<ResponseHead> {
+    let mut headers = [EMPTY_HEADER; 128];
+    let mut resp = Response::new(&mut headers);
+    let res = resp
+        .parse(&raw)
+        .map_err(|_| ProxyError::Parse("While parsing response".to_owned()))?;
+
+    if res.is_partial() {
+        return Err(ProxyError::Parse("Incomplete response".to_owned()));
+    }
+
+    let status = resp
+        .code
+        .ok_or_else(|| ProxyError::Parse("Could not parse status".to_owned()))?;
+    let headers = owned_headers(resp.headers);
+
+    Ok(ResponseHead {
+        status,
+        headers,
+        raw,
+    })
+}
+
+/// Work out how the body of a response is delimited given the request method
+pub fn response_framing(method: &str, resp: &ResponseHead) -> Result<BodyFraming> {
+    if method == "HEAD" || (100..200).contains(&resp.status) || resp.status == 204 || resp.status == 304 {
+        return Ok(BodyFraming::NoBody);
+    }
+
+    headers_framing(&resp.headers).map(|f| f.unwrap_or(BodyFraming::UntilClose))
+}
+
+/// Work out how the body of a request is delimited. Requests without
+/// Transfer-Encoding or Content-Length have no body.
+pub fn request_framing(headers: &[Header]) -> Result<BodyFraming> {
+    match headers_framing(headers)? {
+        // A request body can't be delimited by closing the connection
+        Some(BodyFraming::UntilClose) => Err(ProxyError::Parse(
+            "Unsupported Transfer-Encoding".to_owned(),
+        )),
+        Some(framing) => Ok(framing),
+        None => Ok(BodyFraming::NoBody),
+    }
+}
+
+/// Body framing declared by Transfer-Encoding or Content-Length, if any.
+/// Transfer-Encoding takes precedence as required by RFC 9112.
+pub fn headers_framing(headers: &[Header]) -> Result<Option<BodyFraming>> {
+    if let Some(te) = find_header(headers, "Transfer-Encoding") {
+        let last = te.value_str().rsplit(',').next().unwrap_or("").trim();
+        if last.eq_ignore_ascii_case("chunked") {
+            return Ok(Some(BodyFraming::Chunked));
+        }
+        return Ok(Some(BodyFraming::UntilClose));
+    }
+
+    // Repeated lengths, in separate headers or one list, are only accepted if they all agree (RFC 9112 section 6.3)
+    let mut length = None;
+    for value in headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("Content-Length"))
+        .flat_map(|h| h.value_str().split(','))
+    {
+        let n = value
+            .trim()
+            .parse::<u64>()
+            .map_err(|_| ProxyError::Parse("Invalid Content-Length".to_owned()))?;
+        if length.is_some_and(|length| length != n) {
+            return Err(ProxyError::Parse("Conflicting Content-Length".to_owned()));
+        }
+        length = Some(n);
+    }
+
+    Ok(length.map(BodyFraming::ContentLength))
+}
+
+/// Copy a message body from reader to writer according to its framing.
+/// Chunked bodies are passed through unchanged, chunk headers and trailers
+/// included. Returns the number of bytes written.
+pub async fn relay_body<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut R,
+    writer: &mut W,
+    framing: BodyFraming,
+) -> Result<u64> {
+    match framing {
+        BodyFraming::NoBody => Ok(0),
+        BodyFraming::ContentLength(n) => relay_exact(reader, writer, n).await,
+        BodyFraming::Chunked => relay_chunked(reader, writer).await,
+        BodyFraming::UntilClose => relay_until_close(reader, writer).await,
+    }
+}
+
+async fn write_all<W: AsyncWrite + Unpin>(writer: &mut W, buf: &[u8]) -> Result<()> {
+    writer
+        .write_all(buf)
+        .await
+        .map_err(|e| ProxyError::IO(format!("While writing {:?}", e)))
+}
+
+async fn relay_exact<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(reader: &mut R, writer: &mut W, len: u64) -> Result<u64> {
+    let mut buf = [0u8; RELAY_BUF_SIZE];
+    let mut remaining = len;
+
+    while remaining > 0 {
+        let want = std::cmp::min(remaining, buf.len() as u64) as usize;
+        let n = reader
+            .read(&mut buf[..want])
+            .await
+            .map_err(|e| ProxyError::IO(format!("While reading {:?}", e)))?;
+        if n == 0 {
+            return Err(ProxyError::StreamClosed);
+        }
+        write_all(writer, &buf[..n]).await?;
+        remaining -= n as u64;
+    }
+
+    Ok(len)
+}
+
+async fn relay_until_close<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(reader: &mut R, writer: &mut W) -> Result<u64> {
+    let mut buf = [0u8; RELAY_BUF_SIZE];
+    let mut total = 0u64;
+
+    loop {
+        let n = reader
+            .read(&mut buf)
+            .await
+            .map_err(|e| ProxyError::IO(format!("While reading {:?}", e)))?;
+        if n == 0 {
+            return Ok(total);
+        }
+        write_all(writer, &buf[..n]).await?;
+        total += n as u64;
+    }
+}
+
+/// Read a single CRLF terminated line and forward it verbatim
+async fn relay_line<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(reader: &mut R, writer: &mut W) -> Result<Vec<u8>> {
+    let mut line = Vec::new();
+    let n = reader
+        .read_until(b'\n', &mut line)
+        .await
+        .map_err(|e| ProxyError::IO(format!("While reading {:?}", e)))?;
+    if n == 0 {
+        return Err(ProxyError::StreamClosed);
+    }
+    write_all(writer, &line).await?;
+    Ok(line)
+}
+
+async fn relay_chunked<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(reader: &mut R, writer: &mut W) -> Result<u64> {
+    let mut total = 0u64;
+
+    loop {
+        let line = relay_line(reader, writer).await?;
+        total += line.len() as u64;
+
+        // Chunk size is hex, optionally followed by extensions after ';'
+        let size_str = String::from_utf8_lossy(&line);
+        let size_str = size_str.split(';').next().unwrap_or("").trim();
+        let size = u64::from_str_radix(size_str, 16)
+            .map_err(|_| ProxyError::Parse("Invalid chunk size".to_owned()))?;
+
+        if size == 0 {
+            // Trailer section ends with an empty line
+            loop {
+                let line = relay_line(reader, writer).await?;
+                total += line.len() as u64;
+                if line == b"\r\n" || line == b"\n" {
+                    return Ok(total);
+                }
+            }
+        }
+
+        // Chunk data followed by CRLF
+        total += relay_exact(reader, writer, size).await?;
+        let line = relay_line(reader, writer).await?;
+        total += line.len() as u64;
+    }
+}
+
+/// Where a BodyReader is in the body it reads
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BodyState {
+    Exact(u64),
+    UntilClose,
+    ChunkSize,
+    ChunkData(u64),
+    ChunkEnd,
+    Trailers,
+    Done,
+}
+
+/// Reads a message body piece by piece with its HTTP/1.1 framing taken off, for relaying it to a client whose protocol
+/// frames messages itself. Chunk extensions and trailers are dropped.
+pub struct BodyReader(BodyState);
+
+impl BodyReader {
+    pub fn new(framing: BodyFraming) -> BodyReader {
+        BodyReader(match framing {
+            BodyFraming::NoBody => BodyState::Done,
+            BodyFraming::ContentLength(n) => BodyState::Exact(n),
+            BodyFraming::Chunked => BodyState::ChunkSize,
+            BodyFraming::UntilClose => BodyState::UntilClose,
+        })
+    }
+
+    /// Read the next piece of the body, at most `max` bytes. Returns None once the body has ended.
+    pub async fn next<R: AsyncBufRead + Unpin>(&mut self, reader: &mut R, max: usize) -> Result<Option<Vec<u8>>> {
+        loop {
+            match self.0 {
+                BodyState::Done | BodyState::Exact(0) => {
+                    self.0 = BodyState::Done;
+                    return Ok(None);
+                }
+                BodyState::Exact(n) | BodyState::ChunkData(n) => {
+                    let data = read_some(reader, std::cmp::min(n, max as u64) as usize).await?;
+                    if data.is_empty() {
+                        return Err(ProxyError::StreamClosed);
+                    }
+                    let remaining = n - data.len() as u64;
+                    self.0 = match self.0 {
+                        BodyState::ChunkData(_) if remaining == 0 => BodyState::ChunkEnd,
+                        BodyState::ChunkData(_) => BodyState::ChunkData(remaining),
+                        _ => BodyState::Exact(remaining),
+                    };
+                    return Ok(Some(data));
+                }
+                BodyState::UntilClose => {
+                    let data = read_some(reader, max).await?;
+                    if data.is_empty() {
+                        self.0 = BodyState::Done;
+                        return Ok(None);
+                    }
+                    return Ok(Some(data));
+                }
+                BodyState::ChunkSize => {
+                    let line = read_line(reader).await?;
+                    let size_str = String::from_utf8_lossy(&line);
+                    let size_str = size_str.split(';').next().unwrap_or("").trim();
+                    let size = u64::from_str_radix(size_str, 16)
+                        .map_err(|_| ProxyError::Parse("Invalid chunk size".to_owned()))?;
+                    self.0 = match size {
+                        0 => BodyState::Trailers,
+                        size => BodyState::ChunkData(size),
+                    };
+                }
+                BodyState::ChunkEnd => {
+                    read_line(reader).await?;
+                    self.0 = BodyState::ChunkSize;
+                }
+                BodyState::Trailers => {
+                    let line = read_line(reader).await?;
+                    if line == b"\r\n" || line == b"\n" {
+                        self.0 = BodyState::Done;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Read whatever is available, up to `max` bytes. An empty result means EOF.
+async fn read_some<R: AsyncRead + Unpin>(reader: &mut R, max: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; std::cmp::min(max, RELAY_BUF_SIZE)];
+    let n = reader
+        .read(&mut buf)
+        .await
+        .map_err(|e| ProxyError::IO(format!("While reading {:?}", e)))?;
+    buf.truncate(n);
+    Ok(buf)
+}
+
+/// Read a single line, including its line ending
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut line = Vec::new();
+    let n = reader
+        .read_until(b'\n', &mut line)
+        .await
+        .map_err(|e| ProxyError::IO(format!("While reading {:?}", e)))?;
+    if n == 0 {
+        return Err(ProxyError::StreamClosed);
+    }
+    Ok(line)
+}
+
+#[cfg(test)]
+mod test_http_message {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_head_keeps_body() {
+        let data = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello".to_vec();
+        let mut reader = &data[..];
+
+        let head = read_head(&mut reader).await.unwrap();
+        let resp = parse_response(head).unwrap();
+        assert_eq!(resp.status, 200);
+
+        let framing = response_framing("GET", &resp).unwrap();
+        assert_eq!(framing, BodyFraming::ContentLength(5));
+
+        let mut out = Vec::new();
+        relay_body(&mut reader, &mut out, framing).await.unwrap();
+        assert_eq!(out, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_relay_chunked() {
+        let body = b"4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\nX-Trailer: 1\r\n\r\nextra".to_vec();
+        let mut reader = &body[..];
+        let mut out = Vec::new();
+
+        relay_body(&mut reader, &mut out, BodyFraming::Chunked).await.unwrap();
+        assert_eq!(out, &body[..body.len() - 5]);
+    }
+
+    #[tokio::test]
+    async fn test_body_reader() {
+        let body = b"4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\nX-Trailer: 1\r\n\r\nextra".to_vec();
+        let mut reader = &body[..];
+        let mut body_reader = BodyReader::new(BodyFraming::Chunked);
+        let mut out = Vec::new();
+        while let Some(data) = body_reader.next(&mut reader, 3).await.unwrap() {
+            out.extend_from_slice(&data);
+        }
+        assert_eq!(out, b"Wikipedia");
+        assert_eq!(reader, b"extra");
+
+        let mut reader = &b"hello"[..];
+        let mut body_reader = BodyReader::new(BodyFraming::ContentLength(8));
+        assert_eq!(body_reader.next(&mut reader, 100).await.unwrap().unwrap(), b"hello");
+        assert!(matches!(body_reader.next(&mut reader, 100).await, Err(ProxyError::StreamClosed)));
+    }
+
+    #[test]
+    fn test_request_framing() {
+        let headers = vec![Header::new("Content-Length", b"12")];
+        assert_eq!(
+            request_framing(&headers).unwrap(),
+            BodyFraming::ContentLength(12)
+        );
+
+        let headers = vec![
+            Header::new("Content-Length", b"12"),
+            Header::new("Transfer-Encoding", b"gzip, chunked"),
+        ];
+        assert_eq!(request_framing(&headers).unwrap(), BodyFraming::Chunked);
+
+        let headers = vec![Header::new("Transfer-Encoding", b"gzip")];
+        assert!(request_framing(&headers).is_err());
+
+        let headers = vec![
+            Header::new("Content-Length", b"12"),
+            Header::new("Content-Length", b"12, 12"),
+        ];
+        assert_eq!(request_framing(&headers).unwrap(), BodyFraming::ContentLength(12));
+
+        let headers = vec![
+            Header::new("Content-Length", b"12"),
+            Header::new("Content-Length", b"4"),
+        ];
+        assert!(matches!(request_framing(&headers), Err(ProxyError::Parse(_))));
+        let headers = vec![Header::new("Content-Length", b"12, 4")];
+        assert!(request_framing(&headers).is_err());
+
+        assert_eq!(request_framing(&[]).unwrap(), BodyFraming::NoBody);
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let request = |version: u8, headers: Vec<Header>| HttpRequest {
+            method: "GET".to_owned(),
+            target: "http://example.com/".to_owned(),
+            version,
+            headers,
+            framing: BodyFraming::NoBody,
+        };
+
+        assert!(request(1, vec![]).keep_alive());
+        assert!(!request(1, vec![Header::new("Connection", b"Upgrade, Close")]).keep_alive());
+        assert!(!request(0, vec![]).keep_alive());
+        assert!(request(0, vec![Header::new("Proxy-Connection", b"keep-alive")]).keep_alive());
+        assert!(!request(
+            1,
+            vec![
+                Header::new("Connection", b"close"),
+                Header::new("Proxy-Connection", b"keep-alive"),
+            ]
+        )
+        .keep_alive());
+    }
+
+    #[test]
+    fn test_upgrades() {
+        let mut req = HttpRequest {
+            method: "GET".to_owned(),
+            target: "http://example.com/chat".to_owned(),
+            version: 1,
+            headers: vec![
+                Header::new("Connection", b"keep-alive, Upgrade"),
+                Header::new("Upgrade", b"WebSocket, TLS/1.3"),
+            ],
+            framing: BodyFraming::NoBody,
+        };
+        assert_eq!(req.upgrades(), vec!["websocket", "tls"]);
+
+        req.headers.remove(0);
+        assert!(req.upgrades().is_empty());
+    }
+
+    #[test]
+    fn test_framing_without_body() {
+        let head = b"HTTP/1.1 304 Not Modified\r\nContent-Length: 10\r\n\r\n".to_vec();
+        let resp = parse_response(head).unwrap();
+        assert_eq!(response_framing("GET", &resp).unwrap(), BodyFraming::NoBody);
+
+        let head = b"HTTP/1.1 200 OK\r\n\r\n".to_vec();
+        let resp = parse_response(head).unwrap();
+        assert_eq!(response_framing("HEAD", &resp).unwrap(), BodyFraming::NoBody);
+        assert_eq!(response_framing("GET", &resp).unwrap(), BodyFraming::UntilClose);
+    }
+}
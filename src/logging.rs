@@ -3,114 +3,110 @@
 
 // TcpListener can be removed once the main function is also removed.
 use chrono::{DateTime, Local};
-use std::fs::{File, OpenOptions};
-use std::io::{Error, ErrorKind, Write};
+use std::cell::RefCell;
+use std::fs::OpenOptions;
+use std::future::Future;
+use std::io::{Error, Write};
 use std::net::{SocketAddr, TcpListener};
+use std::sync::OnceLock;
+
+use crate::config::LoggingConfig;
+
+static PATHS: OnceLock<LoggingConfig> = OnceLock::new();
 
 pub enum Event {
     WhiteListDeny,
     BlackListDeny,
+    PolicyDeny,
+    AuthDeny,
     Connection,
     DataTransfer,
     ProxyServer,
+    Overloaded,
+    Timeout,
     SuspiciousActivity,
-    Uncategorized,
 }
 
-pub fn log(addr: SocketAddr, connection_result: &str) -> Result<(), Error> {
-    // Checking to see if the file exists every time seems like overkill. It maybe better to create log.txt on the
-    // server side.
-    let log_file = File::open("log.txt");
-
-    match log_file {
-        Ok(file) => file,
-        // This should only need to happen once per place the host happens.
-        Err(error) => match error.kind() {
-            ErrorKind::NotFound => match File::create("log.txt") {
-                Ok(fc) => fc,
-                Err(e) => panic!("Problem creating log.txt. Reason: {:?}", e),
-            },
-            other_error => {
-                panic!("Problem opening log.txt. Reason: {:?}", other_error);
-            }
-        },
-    };
+tokio::task_local! {
+    // Authenticated user of the connection handled by the current task, if any.
+    static USER: RefCell<Option<String>>;
+}
+
+/// Run a connection's future with its own user, so the user set by one connection never shows up in the events of
+/// another.
+pub async fn user_scope<F: Future>(f: F) -> F::Output {
+    USER.scope(RefCell::new(None), f).await
+}
+
+/// Set the authenticated user for the connection handled by the current task. Every event logged from within its
+/// user_scope names the user until it's set again. Outside of a user_scope this does nothing.
+pub fn set_user(user: Option<String>) {
+    let _ = USER.try_with(|u| *u.borrow_mut() = user);
+}
+
+/// Set where logs are written. Until this is called the default paths in the working directory are used.
+pub fn init(config: &LoggingConfig) {
+    let _ = PATHS.set(config.clone());
+}
+
+fn paths() -> LoggingConfig {
+    PATHS.get().cloned().unwrap_or_default()
+}
 
+pub fn log(addr: SocketAddr, connection_result: &str) -> Result<(), Error> {
     let mut log_file = OpenOptions::new()
-        .write(true)
+        .create(true)
         .append(true)
-        .open("log.txt")
-        .unwrap();
+        .open(paths().connection_log)?;
 
     let time: DateTime<Local> = Local::now();
 
     // This will very likely need to have more added to it as we develop further features, especially for the second
     // deliverable.
-    writeln!(
-        log_file,
-        "{} | {} | {}",
-        addr.to_string(),
-        time.to_string(),
-        connection_result
-    )?;
+    writeln!(log_file, "{} | {} | {}", addr, time, connection_result)?;
 
     Ok(())
 }
 
-pub fn event_log(event: Event, msg: &str) -> Result<(), Error> {
-    let event_file = File::open("event_log.txt");
-
-    match event_file {
-        Ok(file) => file,
-        // This should only need to happen once per place the host happens.
-        Err(error) => match error.kind() {
-            ErrorKind::NotFound => match File::create("event_log.txt") {
-                Ok(fc) => fc,
-                Err(e) => panic!("Problem creating event_log.txt. Reason: {:?}", e),
-            },
-            other_error => {
-                panic!("Problem opening event_log.txt. Reason: {:?}", other_error);
-            }
-        },
-    };
-
-    let mut event_file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .open("event_log.txt")
-        .unwrap();
-
+/// Writes an event to the event log and the console. Failing to write the event log shouldn't take down the
+/// connection that's being logged, so errors are only reported on stderr.
+pub fn event_log(event: Event, msg: &str) {
     let time: DateTime<Local> = Local::now();
     let mut event_msg = String::new();
 
     match event {
         Event::BlackListDeny => event_msg += "[Blacklist Deny]",
         Event::WhiteListDeny => event_msg += "[Whitelist Deny]",
+        Event::AuthDeny => event_msg += "[Auth Deny]",
+        Event::PolicyDeny => event_msg += "[Policy Deny]",
         Event::Connection => event_msg += "[Connection]",
         Event::DataTransfer => event_msg += "[Data Transfer]",
         Event::ProxyServer => event_msg += "[Proxy Server]",
+        Event::Overloaded => event_msg += "[Overloaded]",
+        Event::Timeout => event_msg += "[Timeout]",
         Event::SuspiciousActivity => event_msg += "[Suspicious Activity]",
-        Event::Uncategorized => event_msg += "[Uncategorized]",
-        _ => event_msg += "[Uncategorized]",
     };
 
-    writeln!(
-        event_file,
-        "{} {}: {}",
-        time.format("[%b %d, %Y; %I:%M %p]").to_string(),
-        event_msg,
-        msg
-    )?;
+    let _ = USER.try_with(|u| {
+        if let Some(user) = u.borrow().as_ref() {
+            event_msg += &format!(" [User {}]", user);
+        }
+    });
+
+    let line = format!("{} {}: {}", time.format("[%b %d, %Y; %I:%M %p]"), event_msg, msg);
 
-    // For console logging
-    println!(
-        "{} {}: {}",
-        time.format("[%b %d, %Y; %I:%M %p]").to_string(),
-        event_msg,
-        msg
-    );
+    let path = paths().event_log;
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut f| writeln!(f, "{}", line));
+    if let Err(e) = written {
+        eprintln!("Could not write to {}: {}", path, e);
+    }
 
-    Ok(())
+    // For console logging
+    println!("{}", line);
 }
 
 // Sample running of the log server with single listen. Must be run mulitple times to get multiple log lines.
@@ -121,10 +117,9 @@ fn main() {
     match listener.accept() {
         Ok((_socket, addr)) => {
             println!("new client: {:?}", addr);
-            match log(addr, "OK") {
-                Err(e) => println!("Uncaught issue with the log function: {:?}", e),
-                _ => (),
-            };
+            if let Err(e) = log(addr, "OK") {
+                println!("Uncaught issue with the log function: {:?}", e);
+            }
         }
         Err(e) => println!("couldn't get client {:?}", e),
     }
@@ -1,20 +1,41 @@
+mod admission;
+mod auth;
+mod block_page;
+mod cli;
+mod config;
 mod firewall;
+mod http2;
+mod http_message;
 mod logging;
+mod policy;
 mod proxy_listener;
 mod request_handler;
+mod rules;
+mod socks;
+mod sorting_logs;
+#[cfg(target_os = "linux")]
+mod splice;
 mod statistics;
 
-use std::thread;
-use statistics::generate_statistics;
+use std::process;
+
+use clap::Parser;
+
+use cli::{Cli, Command};
+use config::Config;
+
 fn main() {
-    // Run generate_statistics in a background thread which generates the stats from event_log.txt
-    // every 5s seconds into statistics.txt file.
-    thread::spawn(|| {
-        generate_statistics();
-    });
-    // Block the runtime on the proxy listener
-    proxy_listener::run_listener();
-    println!("Terminating server!");
+    let cli = Cli::parse();
+
+    let config = match Config::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            process::exit(2);
+        }
+    };
+
+    process::exit(cli::run(cli.command.unwrap_or(Command::Serve), config));
 }
 
 #[cfg(test)]
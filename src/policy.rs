@@ -0,0 +1,579 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use publicsuffix::List;
+
+use crate::rules::AddrRule;
+use crate::rules::HostRule;
+
+/// What happens to a request matched by a policy rule. Log rules don't
+/// decide anything, they only note the match in the event log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Allow,
+    Deny,
+    Log,
+}
+
+/// Which rule wins when several allow or deny rules match a request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Precedence {
+    /// The first matching rule in the file
+    FirstMatch,
+    /// The rule with the most specific subject, then the most specific
+    /// object. Ties go to the rule that comes first.
+    MostSpecific,
+}
+
+/// Who a rule applies to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Subject {
+    Any,
+    User(String),
+    Group(String),
+    Addr(AddrRule),
+}
+
+/// What a rule applies to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Any,
+    Addr(AddrRule),
+    Host(HostRule),
+}
+
+/// A destination pattern with an optional inclusive port range
+#[derive(Debug, Clone, PartialEq)]
+pub struct Object {
+    pub target: Target,
+    pub ports: Option<(u16, u16)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicyRule {
+    /// Line of the policy file the rule came from, used in log messages
+    pub line: usize,
+    pub action: Action,
+    pub subject: Subject,
+    pub object: Object,
+    /// Protocol the rule is limited to requests upgrading to, "*" for any. Rules without one apply to every request.
+    pub upgrade: Option<String>,
+}
+
+/// A member of a group is either a user or a range of client addresses
+#[derive(Debug, Clone, PartialEq)]
+pub enum Member {
+    User(String),
+    Addr(AddrRule),
+}
+
+/// The client making a request, as far as the proxy knows it
+#[derive(Debug, Clone)]
+pub struct Client<'a> {
+    pub user: Option<&'a str>,
+    pub addr: IpAddr,
+}
+
+/// The destination of a request. `hosts` holds every name the request uses
+/// for its destination (the CONNECT authority, or the URL host and Host
+/// header) and `addrs` everything they resolved to.
+#[derive(Debug, Clone)]
+pub struct Destination<'a> {
+    pub hosts: &'a [String],
+    pub addrs: &'a [IpAddr],
+    pub port: u16,
+    /// Protocols the request asks to upgrade the connection to, lower case and without versions
+    pub upgrades: &'a [String],
+}
+
+/// Outcome of evaluating the policy for a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verdict {
+    /// Allow or Deny
+    pub action: Action,
+    /// Line of the deciding rule, or None if the default action applied
+    pub line: Option<usize>,
+    /// Lines of log rules that matched
+    pub logged: Vec<usize>,
+}
+
+/// Access policy with rules of the form `action subject object`. See the
+/// README for the file format.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    pub rules: Vec<PolicyRule>,
+    pub groups: HashMap<String, Vec<Member>>,
+    pub precedence: Precedence,
+    /// Allow or Deny, applied when no allow or deny rule matches
+    pub default: Action,
+}
+
+impl Policy {
+    /// Parse a policy file. Lines that can't be parsed are skipped and
+    /// returned alongside the policy with their line number and the reason.
+    pub fn parse(content: &str, psl: Option<&List>) -> (Policy, Vec<(usize, String)>) {
+        let mut policy = Policy {
+            rules: vec![],
+            groups: HashMap::new(),
+            precedence: Precedence::FirstMatch,
+            default: Action::Deny,
+        };
+        let mut errors = vec![];
+
+        for (n, line) in content.lines().enumerate() {
+            let line = match line.split_once('#') {
+                Some((before, _)) => before.trim(),
+                None => line.trim(),
+            };
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Err(e) = policy.parse_line(n + 1, line, psl) {
+                errors.push((n + 1, e));
+            }
+        }
+
+        (policy, errors)
+    }
+
+    fn parse_line(&mut self, n: usize, line: &str, psl: Option<&List>) -> Result<(), String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        match words[0] {
+            "precedence" => {
+                self.precedence = match words.get(1..) {
+                    Some(["first-match"]) => Precedence::FirstMatch,
+                    Some(["most-specific"]) => Precedence::MostSpecific,
+                    _ => return Err("expected precedence first-match|most-specific".to_owned()),
+                };
+            }
+
+            "default" => {
+                self.default = match words.get(1..) {
+                    Some(["allow"]) => Action::Allow,
+                    Some(["deny"]) => Action::Deny,
+                    _ => return Err("expected default allow|deny".to_owned()),
+                };
+            }
+
+            "group" => {
+                if words.len() < 2 {
+                    return Err("expected group name members...".to_owned());
+                }
+
+                let mut members = vec![];
+                for word in &words[2..] {
+                    members.push(match word.parse::<AddrRule>() {
+                        Ok(rule) => Member::Addr(rule),
+                        Err(_) => Member::User(word.to_string()),
+                    });
+                }
+                self.groups
+                    .entry(words[1].to_owned())
+                    .or_default()
+                    .extend(members);
+            }
+
+            action => {
+                let action = match action {
+                    "allow" => Action::Allow,
+                    "deny" => Action::Deny,
+                    "log" => Action::Log,
+                    _ => return Err(format!("unknown directive {}", action)),
+                };
+                if words.len() != 3 && words.len() != 4 {
+                    return Err("expected action subject object [upgrade:PROTOCOL]".to_owned());
+                }
+
+                self.rules.push(PolicyRule {
+                    line: n,
+                    action,
+                    subject: parse_subject(words[1])?,
+                    object: parse_object(words[2], psl)?,
+                    upgrade: words.get(3).map(|w| parse_upgrade(w)).transpose()?,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Evaluate the policy for a request
+    pub fn evaluate(&self, client: &Client, dst: &Destination, psl: Option<&List>) -> Verdict {
+        let mut logged = vec![];
+        let mut decision: Option<&PolicyRule> = None;
+
+        for rule in &self.rules {
+            if !self.subject_matches(&rule.subject, client) {
+                continue;
+            }
+
+            // Allow rules have to cover every name and address of the destination, while a deny or log rule
+            // matching any one of them is enough. This way a request can't slip past a deny rule, or into an allow
+            // rule, by naming a different host in the URL and Host header.
+            if !object_matches(&rule.object, dst, rule.action == Action::Allow, psl) {
+                continue;
+            }
+            if !upgrade_matches(rule.upgrade.as_deref(), dst.upgrades, rule.action == Action::Allow) {
+                continue;
+            }
+
+            match rule.action {
+                Action::Log => logged.push(rule.line),
+                _ => match (self.precedence, decision) {
+                    (_, None) => decision = Some(rule),
+                    (Precedence::FirstMatch, Some(_)) => {}
+                    (Precedence::MostSpecific, Some(current)) => {
+                        if self.specificity(rule) > self.specificity(current) {
+                            decision = Some(rule);
+                        }
+                    }
+                },
+            }
+        }
+
+        match decision {
+            Some(rule) => Verdict {
+                action: rule.action,
+                line: Some(rule.line),
+                logged,
+            },
+            None => Verdict {
+                action: self.default,
+                line: None,
+                logged,
+            },
+        }
+    }
+
+    fn subject_matches(&self, subject: &Subject, client: &Client) -> bool {
+        match subject {
+            Subject::Any => true,
+            Subject::User(user) => client.user == Some(user.as_str()),
+            Subject::Addr(rule) => rule.matches(&client.addr),
+            Subject::Group(group) => match self.groups.get(group) {
+                Some(members) => members.iter().any(|member| match member {
+                    Member::User(user) => client.user == Some(user.as_str()),
+                    Member::Addr(rule) => rule.matches(&client.addr),
+                }),
+                None => false,
+            },
+        }
+    }
+
+    /// Ordering key for most-specific precedence: subject first, then object
+    fn specificity(&self, rule: &PolicyRule) -> (u32, u32) {
+        let subject = match &rule.subject {
+            Subject::Any => 0,
+            Subject::Addr(addr) => 1000 + addr_specificity(addr),
+            Subject::Group(_) => 2000,
+            Subject::User(_) => 3000,
+        };
+
+        let target = match &rule.object.target {
+            Target::Any => 0,
+            Target::Host(HostRule::Registrable(_)) => 100,
+            Target::Host(HostRule::Domain(d)) | Target::Host(HostRule::Subdomains(d)) => {
+                200 + d.split('.').count() as u32
+            }
+            Target::Addr(addr) => 300 + addr_specificity(addr),
+            Target::Host(HostRule::Exact(_)) => 500,
+        };
+
+        // A port restriction makes an otherwise equal rule more specific
+        let port = match rule.object.ports {
+            Some((lo, hi)) if lo == hi => 2,
+            Some(_) => 1,
+            None => 0,
+        };
+
+        // And so does limiting it to upgrades
+        let upgrade = rule.upgrade.is_some() as u32;
+
+        (subject, (target * 4 + port) * 2 + upgrade)
+    }
+}
+
+/// Rough size of the address block a rule covers, larger is narrower. IPv4
+/// prefixes are scaled to IPv6 so the two compare sensibly.
+fn addr_specificity(rule: &AddrRule) -> u32 {
+    match rule {
+        AddrRule::Cidr(IpAddr::V4(_), prefix) => 96 + *prefix as u32,
+        AddrRule::Cidr(IpAddr::V6(_), prefix) => *prefix as u32,
+        AddrRule::Wildcard(octets) => 96 + 8 * octets.iter().filter(|o| o.is_some()).count() as u32,
+        AddrRule::Range(_, _) => 96,
+    }
+}
+
+fn object_matches(object: &Object, dst: &Destination, all: bool, psl: Option<&List>) -> bool {
+    if let Some((lo, hi)) = object.ports {
+        if dst.port < lo || dst.port > hi {
+            return false;
+        }
+    }
+
+    match &object.target {
+        Target::Any => true,
+        Target::Host(rule) => match all {
+            true => !dst.hosts.is_empty() && dst.hosts.iter().all(|h| rule.matches(h, psl)),
+            false => dst.hosts.iter().any(|h| rule.matches(h, psl)),
+        },
+        Target::Addr(rule) => match all {
+            true => !dst.addrs.is_empty() && dst.addrs.iter().all(|a| rule.matches(a)),
+            false => dst.addrs.iter().any(|a| rule.matches(a)),
+        },
+    }
+}
+
+/// Like objects, upgrade conditions of allow rules have to cover every protocol the request asks for
+fn upgrade_matches(rule: Option<&str>, upgrades: &[String], all: bool) -> bool {
+    let protocol = match rule {
+        None => return true,
+        Some(protocol) => protocol,
+    };
+    let matches = |upgrade: &String| protocol == "*" || protocol == upgrade;
+
+    match all {
+        true => !upgrades.is_empty() && upgrades.iter().all(matches),
+        false => upgrades.iter().any(matches),
+    }
+}
+
+/// Parse `upgrade:PROTOCOL`, where PROTOCOL is a name such as websocket, or `*`
+fn parse_upgrade(s: &str) -> Result<String, String> {
+    match s.strip_prefix("upgrade:") {
+        Some(protocol) if !protocol.is_empty() => Ok(protocol.to_ascii_lowercase()),
+        _ => Err(format!("expected upgrade:PROTOCOL instead of {}", s)),
+    }
+}
+
+fn parse_subject(s: &str) -> Result<Subject, String> {
+    if s == "*" {
+        return Ok(Subject::Any);
+    }
+    if let Some(user) = s.strip_prefix("user:") {
+        return Ok(Subject::User(user.to_owned()));
+    }
+    if let Some(group) = s.strip_prefix("group:") {
+        return Ok(Subject::Group(group.to_owned()));
+    }
+
+    s.parse::<AddrRule>()
+        .map(Subject::Addr)
+        .map_err(|e| format!("invalid subject: {}", e))
+}
+
+/// Parse `target[:ports]`, where target is `*`, an address rule or a
+/// hostname rule. IPv6 targets with a port need brackets, as in
+/// `[2001:db8::/32]:443`.
+fn parse_object(s: &str, psl: Option<&List>) -> Result<Object, String> {
+    let (target, ports) = match s.strip_prefix('[') {
+        Some(rest) => {
+            let (target, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| format!("missing ] in {}", s))?;
+            match rest.strip_prefix(':') {
+                Some(ports) => (target, Some(ports)),
+                None if rest.is_empty() => (target, None),
+                None => return Err(format!("invalid object {}", s)),
+            }
+        }
+        None => {
+            let unprefixed = s.strip_prefix("site:").unwrap_or(s);
+            match unprefixed.rsplit_once(':') {
+                // A colon left in the target means it's a bare IPv6 address, not a port
+                Some((target, ports)) if !target.contains(':') => {
+                    (&s[..s.len() - ports.len() - 1], Some(ports))
+                }
+                _ => (s, None),
+            }
+        }
+    };
+
+    let ports = match ports {
+        None | Some("*") => None,
+        Some(p) => Some(parse_ports(p).ok_or_else(|| format!("invalid port {}", p))?),
+    };
+
+    let target = if target == "*" {
+        Target::Any
+    } else if let Ok(rule) = target.parse::<AddrRule>() {
+        Target::Addr(rule)
+    } else {
+        Target::Host(HostRule::parse(target, psl)?)
+    };
+
+    Ok(Object { target, ports })
+}
+
+fn parse_ports(s: &str) -> Option<(u16, u16)> {
+    match s.split_once('-') {
+        Some((lo, hi)) => {
+            let (lo, hi) = (lo.parse().ok()?, hi.parse().ok()?);
+            match lo <= hi {
+                true => Some((lo, hi)),
+                false => None,
+            }
+        }
+        None => {
+            let port = s.parse().ok()?;
+            Some((port, port))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_policy {
+
+    use super::*;
+
+    const POLICY: &str = "
+        # Contractors only get to GitHub over TLS
+        group admins alice
+        group contractors carol 10.20.0.0/16
+        allow group:admins *
+        allow group:contractors *.github.com:443
+        deny  group:contractors *
+        log   user:bob *
+        allow 10.0.0.0/8 *
+    ";
+
+    fn eval(policy: &Policy, user: Option<&str>, addr: &str, host: &str, port: u16) -> Verdict {
+        let hosts = vec![host.to_owned()];
+        let addrs = vec!["198.51.100.7".parse().unwrap()];
+        let client = Client {
+            user,
+            addr: addr.parse().unwrap(),
+        };
+        let dst = Destination {
+            hosts: &hosts,
+            addrs: &addrs,
+            port,
+            upgrades: &[],
+        };
+        policy.evaluate(&client, &dst, None)
+    }
+
+    #[test]
+    fn test_first_match() {
+        let (policy, errors) = Policy::parse(POLICY, None);
+        assert!(errors.is_empty());
+
+        let verdict = eval(&policy, Some("alice"), "192.0.2.1", "example.com", 80);
+        assert_eq!((verdict.action, verdict.line), (Action::Allow, Some(5)));
+
+        let verdict = eval(&policy, Some("carol"), "192.0.2.1", "api.github.com", 443);
+        assert_eq!(verdict.action, Action::Allow);
+        let verdict = eval(&policy, Some("carol"), "192.0.2.1", "api.github.com", 22);
+        assert_eq!(verdict.action, Action::Deny);
+        let verdict = eval(&policy, None, "10.20.1.1", "example.com", 443);
+        assert_eq!((verdict.action, verdict.line), (Action::Deny, Some(7)));
+
+        let verdict = eval(&policy, Some("bob"), "10.1.1.1", "example.com", 80);
+        assert_eq!(verdict.action, Action::Allow);
+        assert_eq!(verdict.logged, vec![8]);
+
+        let verdict = eval(&policy, Some("bob"), "192.0.2.1", "example.com", 80);
+        assert_eq!((verdict.action, verdict.line), (Action::Deny, None));
+    }
+
+    #[test]
+    fn test_most_specific() {
+        let (policy, _) = Policy::parse(
+            "precedence most-specific
+             deny  group:contractors *
+             allow group:contractors *.github.com:443
+             deny  * .github.com
+             allow user:carol gist.github.com
+             group contractors carol dave",
+            None,
+        );
+
+        let verdict = eval(&policy, Some("dave"), "192.0.2.1", "api.github.com", 443);
+        assert_eq!((verdict.action, verdict.line), (Action::Allow, Some(3)));
+        let verdict = eval(&policy, Some("dave"), "192.0.2.1", "example.com", 443);
+        assert_eq!(verdict.action, Action::Deny);
+        let verdict = eval(&policy, Some("carol"), "192.0.2.1", "gist.github.com", 80);
+        assert_eq!((verdict.action, verdict.line), (Action::Allow, Some(5)));
+        let verdict = eval(&policy, None, "192.0.2.1", "gist.github.com", 443);
+        assert_eq!((verdict.action, verdict.line), (Action::Deny, Some(4)));
+    }
+
+    #[test]
+    fn test_allow_must_cover_every_host() {
+        let (policy, _) = Policy::parse("allow * .github.com", None);
+        let hosts = vec!["github.com".to_owned(), "evil.example".to_owned()];
+        let client = Client {
+            user: None,
+            addr: "192.0.2.1".parse().unwrap(),
+        };
+        let dst = Destination {
+            hosts: &hosts,
+            addrs: &[],
+            port: 80,
+            upgrades: &[],
+        };
+        assert_eq!(policy.evaluate(&client, &dst, None).action, Action::Deny);
+    }
+
+    #[test]
+    fn test_upgrades() {
+        let (policy, errors) = Policy::parse(
+            "deny  * chat.example.com upgrade:websocket
+             allow * *.example.com
+             allow * * upgrade:h2c",
+            None,
+        );
+        assert!(errors.is_empty());
+
+        let verdict = |host: &str, upgrades: &[&str]| {
+            let hosts = vec![host.to_owned()];
+            let upgrades: Vec<String> = upgrades.iter().map(|u| u.to_string()).collect();
+            let client = Client {
+                user: None,
+                addr: "192.0.2.1".parse().unwrap(),
+            };
+            let dst = Destination {
+                hosts: &hosts,
+                addrs: &[],
+                port: 80,
+                upgrades: &upgrades,
+            };
+            let verdict = policy.evaluate(&client, &dst, None);
+            (verdict.action, verdict.line)
+        };
+
+        assert_eq!(verdict("chat.example.com", &[]), (Action::Allow, Some(2)));
+        assert_eq!(verdict("chat.example.com", &["websocket"]), (Action::Deny, Some(1)));
+        assert_eq!(verdict("other.example", &["h2c"]), (Action::Allow, Some(3)));
+        assert_eq!(verdict("other.example", &["h2c", "websocket"]), (Action::Deny, None));
+
+        let (_, errors) = Policy::parse("allow * * websocket", None);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_object() {
+        let object = parse_object("[2001:db8::/32]:443", None).unwrap();
+        assert!(matches!(object.target, Target::Addr(_)));
+        assert_eq!(object.ports, Some((443, 443)));
+
+        let object = parse_object("2001:db8::1", None).unwrap();
+        assert!(matches!(object.target, Target::Addr(_)));
+        assert_eq!(object.ports, None);
+
+        let object = parse_object("site:example.co.uk:8000-8080", None).unwrap();
+        assert_eq!(
+            object.target,
+            Target::Host(HostRule::Registrable("example.co.uk".to_owned()))
+        );
+        assert_eq!(object.ports, Some((8000, 8080)));
+
+        assert_eq!(parse_object("*:*", None).unwrap().ports, None);
+        assert!(parse_object("example.com:http", None).is_err());
+
+        let (_, errors) = Policy::parse("allow bogus:thing *\nfrobnicate\nallow *", None);
+        assert_eq!(errors.len(), 3);
+    }
+}
@@ -1,14 +1,25 @@
 use std::net::SocketAddr;
-use std::net::TcpListener;
-use std::net::TcpStream;
 use std::sync::Arc;
-use std::sync::Mutex;
-use std::thread;
+use std::time::Duration;
 
-use crate::firewall::Firewall;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::admission;
+use crate::admission::Admission;
+use crate::admission::Admitted;
+use crate::block_page::SharedBlockPage;
+use crate::config::Anonymity;
+use crate::config::Config;
+use crate::firewall;
+use crate::firewall::SharedFirewall;
+use crate::http2;
 use crate::logging;
 use crate::logging::Event;
 use crate::request_handler::process_connection;
+use crate::request_handler::reject_overloaded;
+use crate::socks;
 
 // Req Handling error type
 pub type Result<T> = std::result::Result<T, ProxyError>;
@@ -17,92 +28,366 @@ pub type Result<T> = std::result::Result<T, ProxyError>;
 pub enum ProxyError {
     IO(String),
     Parse(String),
-    Other(String),
     StreamClosed,
+    HeaderTooLarge,
+    MethodNotAllowed(String),
+    NotImplemented(String),
+    CannotResolveDest,
     CannotConnectToDest,
+    InvalidResponse(String),
+    Overloaded,
     IOBlocked,
     WhiteListDeny,
     BlackListDeny,
+    PolicyDeny,
+    ProxyAuthRequired,
+    LoopDetected,
+    RequestTimeout,
+    ConnectTimeout,
+    TunnelIdleTimeout,
+    TunnelLifetimeExceeded,
+}
+
+/// How an error is reported to the client: the status line, the RFC 9209 Proxy-Status error type and a short
+/// explanation for the body and the Proxy-Status details
+#[derive(Debug, PartialEq)]
+pub struct ErrorReply {
+    pub status: u16,
+    pub reason: &'static str,
+    pub error_type: &'static str,
+    pub details: String,
 }
 
-/// Open connection to the target and return the tcp stream
-pub fn get_target_stream(addr: &str) -> Result<TcpStream> {
-    match TcpStream::connect(addr) {
-        Ok(a) => Ok(a),
-        Err(_) => Err(ProxyError::CannotConnectToDest),
+impl ProxyError {
+    /// The response the client gets for this error. Errors that happen once the client's connection is gone or a
+    /// response is already on its way have none.
+    pub fn reply(&self) -> Option<ErrorReply> {
+        let (status, reason, error_type, details) = match self {
+            ProxyError::Parse(msg) => (400, "Bad Request", "http_request_error", msg.clone()),
+            ProxyError::HeaderTooLarge => (
+                431,
+                "Request Header Fields Too Large",
+                "http_request_error",
+                "The request head is too large".to_owned(),
+            ),
+            ProxyError::MethodNotAllowed(method) => (
+                405,
+                "Method Not Allowed",
+                "http_request_denied",
+                format!("{} requests are not forwarded", method),
+            ),
+            ProxyError::NotImplemented(msg) => (501, "Not Implemented", "http_request_error", msg.clone()),
+            ProxyError::RequestTimeout => (
+                408,
+                "Request Timeout",
+                "http_request_error",
+                "The request was not received in time".to_owned(),
+            ),
+            ProxyError::WhiteListDeny => (
+                403,
+                "Forbidden",
+                "http_request_denied",
+                "The client is not in the whitelist".to_owned(),
+            ),
+            ProxyError::BlackListDeny => (
+                403,
+                "Forbidden",
+                "http_request_denied",
+                "The destination is in the blacklist".to_owned(),
+            ),
+            ProxyError::PolicyDeny => (
+                403,
+                "Forbidden",
+                "http_request_denied",
+                "The request is denied by the access policy".to_owned(),
+            ),
+            ProxyError::ProxyAuthRequired => (
+                407,
+                "Proxy Authentication Required",
+                "http_request_denied",
+                "Valid proxy credentials are required".to_owned(),
+            ),
+            ProxyError::LoopDetected => (
+                508,
+                "Loop Detected",
+                "proxy_loop_detected",
+                "The request would loop back through the proxy".to_owned(),
+            ),
+            ProxyError::CannotResolveDest => (
+                502,
+                "Bad Gateway",
+                "dns_error",
+                "The destination could not be resolved".to_owned(),
+            ),
+            ProxyError::CannotConnectToDest => (
+                502,
+                "Bad Gateway",
+                "destination_unavailable",
+                "The destination could not be reached".to_owned(),
+            ),
+            ProxyError::InvalidResponse(msg) => (502, "Bad Gateway", "http_protocol_error", msg.clone()),
+            ProxyError::ConnectTimeout => (
+                504,
+                "Gateway Timeout",
+                "connection_timeout",
+                "Connecting to the destination timed out".to_owned(),
+            ),
+            ProxyError::Overloaded => (
+                503,
+                "Service Unavailable",
+                "proxy_internal_error",
+                "Too many connections, try again later".to_owned(),
+            ),
+            ProxyError::IO(_)
+            | ProxyError::StreamClosed
+            | ProxyError::IOBlocked
+            | ProxyError::TunnelIdleTimeout
+            | ProxyError::TunnelLifetimeExceeded => return None,
+        };
+
+        Some(ErrorReply {
+            status,
+            reason,
+            error_type,
+            details,
+        })
     }
 }
 
-// Create a simple TcpListener for given ip and port
-fn get_listener(ip: &String, port: &String) -> TcpListener {
-    // Will remove all debugging lines after testing
+/// Resolve the host of a request target into the addresses it can be
+/// reached at. Nothing is connected to at this point so the firewall can
+/// vet the addresses first.
+pub async fn resolve_target(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    match tokio::net::lookup_host((host, port)).await {
+        Ok(addrs) => {
+            let addrs: Vec<SocketAddr> = addrs.collect();
+            if addrs.is_empty() {
+                Err(ProxyError::CannotResolveDest)
+            } else {
+                Ok(addrs)
+            }
+        }
+        Err(_) => Err(ProxyError::CannotResolveDest),
+    }
+}
+
+/// Open connection to the first reachable of the approved target addresses
+/// and return the tcp stream. Each address gets `timeout` to answer, and if
+/// none could be reached because they timed out the error says so.
+pub async fn get_target_stream(addrs: &[SocketAddr], timeout: Duration) -> Result<TcpStream> {
+    let mut timed_out = false;
+
+    for addr in addrs {
+        match tokio::time::timeout(timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(_)) => {}
+            Err(_) => {
+                logging::event_log(
+                    Event::Timeout,
+                    &format!("Connecting to {} timed out after {}s", addr, timeout.as_secs()),
+                );
+                timed_out = true;
+            }
+        }
+    }
+
+    match timed_out {
+        true => Err(ProxyError::ConnectTimeout),
+        false => Err(ProxyError::CannotConnectToDest),
+    }
+}
+
+// Create a simple TcpListener for the given address
+async fn get_listener(addr: &SocketAddr) -> Result<TcpListener> {
     logging::event_log(
         Event::ProxyServer,
-        &format!("Starting proxy listener on {}:{}", ip, port),
+        &format!("Starting proxy listener on {}", addr),
     );
 
-    let listener = TcpListener::bind(format!("{}:{}", ip, port));
-    let listener_handler = match listener {
+    let listener_handler = match TcpListener::bind(addr).await {
         Ok(sock) => sock,
         Err(err) => {
             logging::event_log(Event::ProxyServer, &format!("Encountered error {}", err));
-            panic!("Could not start server!");
+            return Err(ProxyError::IO(format!("Could not listen on {}: {}", addr, err)));
         }
     };
 
     logging::event_log(Event::ProxyServer, "Listener started");
 
-    listener_handler
+    Ok(listener_handler)
 }
 
-fn whitelist_check(addr: &SocketAddr, fwall: Arc<Mutex<Firewall>>) -> bool {
-    let mut _fwall = fwall.lock().unwrap();
-    _fwall.in_whitelist(addr.ip().to_string().as_str())
+/// What clients of a listener speak
+#[derive(Clone)]
+enum Frontend {
+    /// HTTP/1.x, or HTTP/2 with prior knowledge
+    Http,
+    /// HTTP/2 over TLS
+    Tls(TlsAcceptor),
+    /// SOCKS5, or SOCKS4 and SOCKS4a
+    Socks,
 }
 
-pub fn run_listener() {
-    let ip = String::from("127.0.0.1");
-    let port = String::from("7878");
-
-    let listener = get_listener(&ip, &port);
-    let mut firewall = Arc::new(Mutex::new(Firewall::new()));
-
-    for mut stream in listener.incoming() {
-        match stream {
-            Ok(mut stream) => {
-                let fwall = Arc::clone(&firewall);
-
-                thread::spawn(move || {
-                    match process_connection(&mut stream, fwall) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            logging::event_log(Event::Connection, &format!("Got error: {:?}", e));
-                        }
-                    };
-                });
+/// Handle one connection once it's its turn and note its outcome in the connection log
+async fn handle_connection(
+    mut stream: TcpStream,
+    frontend: Frontend,
+    admitted: Admitted,
+    firewall: Arc<SharedFirewall>,
+    block_page: Arc<SharedBlockPage>,
+    config: Arc<Config>,
+    anonymity: Anonymity,
+) {
+    let turn = admitted.wait_turn().await;
+    let peer = stream.peer_addr();
+
+    let result = admission::hold(admitted, turn, async {
+        match frontend {
+            Frontend::Http => process_connection(&mut stream, &firewall, &block_page, &config, anonymity).await,
+            Frontend::Tls(acceptor) => {
+                http2::serve_tls(stream, acceptor, &firewall, &block_page, &config, anonymity).await
             }
+            Frontend::Socks => socks::process_connection(&mut stream, &firewall, &config).await,
+        }
+    })
+    .await;
+    if let Err(e) = &result {
+        logging::event_log(Event::Connection, &format!("Got error: {:?}", e));
+    };
+
+    // One line per connection in the connection log, for `shallot logs sort`
+    if let Ok(addr) = peer {
+        let outcome = match result {
+            Ok(()) => "OK".to_owned(),
+            Err(e) => format!("{:?}", e),
+        };
+        if let Err(e) = logging::log(addr, &outcome) {
+            eprintln!("Could not write the connection log: {}", e);
+        }
+    }
+}
+
+/// Accept connections on one listener and start a task for each. When too many connections are already being handled
+/// or waiting, the client is turned away instead, with 503 if it speaks plain HTTP.
+async fn accept_connections(
+    listener: TcpListener,
+    frontend: Frontend,
+    anonymity: Anonymity,
+    admission: Admission,
+    firewall: Arc<SharedFirewall>,
+    block_page: Arc<SharedBlockPage>,
+    config: Arc<Config>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => match admission.try_admit() {
+                Some(admitted) => {
+                    let firewall = Arc::clone(&firewall);
+                    let block_page = Arc::clone(&block_page);
+                    let config = Arc::clone(&config);
+                    tokio::spawn(logging::user_scope(handle_connection(
+                        stream,
+                        frontend.clone(),
+                        admitted,
+                        firewall,
+                        block_page,
+                        config,
+                        anonymity,
+                    )));
+                }
+                None => {
+                    logging::event_log(
+                        Event::Overloaded,
+                        &format!("Too many connections, turned away {}", peer),
+                    );
+                    if let Frontend::Http = frontend {
+                        reject_overloaded(&stream, config.limits.retry_after_secs);
+                    }
+                }
+            },
 
             Err(e) => {
                 logging::event_log(
                     Event::Connection,
                     &format!("Could not accept connection {:?}", e),
                 );
+                // Usually out of file descriptors, give connections a moment to finish instead of spinning
+                tokio::time::sleep(Duration::from_millis(100)).await;
             }
         }
     }
 }
 
+/// Bind every configured listen address and serve connections on all of them. Only returns if an address can't be
+/// bound.
+pub fn run_listener(config: Arc<Config>) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| ProxyError::IO(format!("Could not start the runtime: {}", e)))?;
+
+    runtime.block_on(serve(config))
+}
+
+async fn serve(config: Arc<Config>) -> Result<()> {
+    let firewall = Arc::new(SharedFirewall::new(&config.lists));
+    let block_page = Arc::new(SharedBlockPage::new(&config.block_page));
+    tokio::spawn(firewall::watch(Arc::clone(&firewall), SharedFirewall::reload));
+    tokio::spawn(firewall::watch(Arc::clone(&block_page), SharedBlockPage::reload));
+    // One limit for all listeners, so it applies to the server as a whole
+    let admission = Admission::new(config.limits.workers, config.limits.accept_queue);
+    admission::limit_tunnels(config.limits.tunnels);
+
+    // Bind everything first so a bad address is reported before any connection is accepted
+    let mut listeners = vec![];
+    for addr in config.listen_addresses() {
+        listeners.push((get_listener(&addr).await?, Frontend::Http, config.listener.anonymity_of(&addr)));
+    }
+    let tls_addresses = config.tls_addresses();
+    if !tls_addresses.is_empty() {
+        let acceptor = http2::tls_acceptor(&config.http2)?;
+        for addr in tls_addresses {
+            let frontend = Frontend::Tls(acceptor.clone());
+            listeners.push((get_listener(&addr).await?, frontend, config.listener.anonymity_of(&addr)));
+        }
+    }
+    // SOCKS doesn't forward headers, so the anonymity level doesn't matter to it
+    for addr in config.socks_addresses() {
+        listeners.push((get_listener(&addr).await?, Frontend::Socks, config.listener.anonymity));
+    }
+
+    let tasks: Vec<tokio::task::JoinHandle<()>> = listeners
+        .into_iter()
+        .map(|(listener, frontend, anonymity)| {
+            tokio::spawn(accept_connections(
+                listener,
+                frontend,
+                anonymity,
+                admission.clone(),
+                Arc::clone(&firewall),
+                Arc::clone(&block_page),
+                Arc::clone(&config),
+            ))
+        })
+        .collect();
+
+    for task in tasks {
+        let _ = task.await;
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod test_proxy_listener {
 
     use super::get_listener;
-    use super::run_listener;
 
-    #[test]
-    fn test_listener_init() {
-        let ip: String = String::from("127.0.0.1");
-        let port: String = String::from("8080");
+    #[tokio::test]
+    async fn test_listener_init() {
+        let addr = "127.0.0.1:8080".parse().unwrap();
 
-        let result = get_listener(&ip, &port);
+        let result = get_listener(&addr).await;
+        assert!(result.is_ok());
     }
 }
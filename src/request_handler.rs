@@ -1,90 +1,181 @@
 use std::fmt::Debug;
+use std::fmt::Display;
+use std::future;
+use std::io::Cursor;
 use std::io::ErrorKind::WouldBlock;
-use std::io::Read;
-use std::io::Write;
 
+use std::net::IpAddr;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::sync::Mutex;
-
-use std::net::Shutdown;
-use std::net::TcpStream;
 
 use httparse::{Request, EMPTY_HEADER};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio::time::Instant;
+use url::Url;
 
-use std::str;
-
+use crate::admission;
+use crate::admission::TunnelSlot;
+use crate::auth::AuthResult;
+use crate::block_page;
+use crate::block_page::Denial;
+use crate::block_page::SharedBlockPage;
+use crate::config::Anonymity;
+use crate::config::Config;
+use crate::config::TimeoutsConfig;
 use crate::firewall::Firewall;
+use crate::firewall::SharedFirewall;
+use crate::http2;
+use crate::http_message;
+use crate::http_message::BodyFraming;
+use crate::http_message::Header;
+use crate::http_message::HttpRequest;
+use crate::http_message::ResponseHead;
 use crate::logging;
 use crate::logging::Event;
+use crate::policy::Action;
+use crate::policy::Client;
+use crate::policy::Destination;
 use crate::proxy_listener::get_target_stream;
+use crate::proxy_listener::resolve_target;
+use crate::proxy_listener::ErrorReply;
 use crate::proxy_listener::ProxyError;
 use crate::proxy_listener::Result;
+#[cfg(target_os = "linux")]
+use crate::splice;
 
 /// HTTP responses from the proxy server
 /// HTTP response for 200 OK
 const HTTP_OK: &[u8] = "HTTP/1.1 200 OK\r\n\r\n".as_bytes();
-const HTTP_NOT_AUTH: &[u8] = "HTTP/1.1 403 Forbidden\r\n\r\n".as_bytes();
+const PROXY_AUTHENTICATE: &str = "Basic realm=\"Shallot\", charset=\"UTF-8\"";
+const HTTP_CONTINUE: &[u8] = "HTTP/1.1 100 Continue\r\n\r\n".as_bytes();
+
+/// Headers that only apply to one connection and are never forwarded (RFC 9110 section 7.6.1). The Proxy-* ones are
+/// between the client and this proxy.
+const HOP_BY_HOP: [&str; 7] = [
+    "Connection",
+    "Proxy-Connection",
+    "Keep-Alive",
+    "TE",
+    "Upgrade",
+    "Proxy-Authorization",
+    "Proxy-Authenticate",
+];
+
+/// Headers that are forwarded even if Connection names them. Bodies are relayed as these frame them, so the other end
+/// has to see them too or it would read the body differently.
+const FRAMING: [&str; 3] = ["Host", "Content-Length", "Transfer-Encoding"];
 
-/// Parse request into a well defined request type
-/// For now, the proxy only supports GET and CONNECT requests
+/// Parse request into a well defined request type. CONNECT requests open a
+/// tunnel, every other method is forwarded to the origin as an HttpRequest.
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum ReqType {
-    CONNECT(String),
-    GET(String),
+    CONNECT(String, Vec<Header>),
+    HTTP(HttpRequest),
 }
 
 /// Determine request type. Get the raw request and parse it into
-/// a ReqType.
-fn determine_request(buf: &[u8]) -> Result<ReqType> {
+/// a ReqType. Also returns the length of the request head, anything past it
+/// belongs to the request body. Returns None if the head isn't complete yet.
+fn determine_request(buf: &[u8]) -> Result<Option<(ReqType, usize)>> {
     let mut headers = [EMPTY_HEADER; 4096];
     let mut req = Request::new(&mut headers);
-    let res = req
-        .parse(&buf)
-        .map_err(|_| ProxyError::Parse("While parsing request".to_owned()))?;
-
-    if res.is_partial() {
-        Err(ProxyError::Parse("Incomplete request".to_owned()))
-    } else {
-        match req.method {
-            Some("CONNECT") => match req.path {
-                Some(a) => Ok(ReqType::CONNECT(a.to_string())),
-                None => Err(ProxyError::Parse("Could not parse path".to_owned())),
-            },
-
-            Some("GET") => match req.path {
-                Some(p) => Ok(ReqType::GET(p.to_string())),
-                None => Err(ProxyError::Parse("Could not parse path".to_owned())),
-            },
-
-            Some(p) => Err(ProxyError::Parse(format!("Could not parse {}", p))),
-            None => Err(ProxyError::Parse("Could not parse request".to_owned())),
+    let res = req.parse(buf).map_err(|e| match e {
+        httparse::Error::TooManyHeaders => ProxyError::HeaderTooLarge,
+        _ => ProxyError::Parse("While parsing request".to_owned()),
+    })?;
+
+    let head_len = match res {
+        httparse::Status::Complete(n) => n,
+        httparse::Status::Partial => return Ok(None),
+    };
+
+    let path = req
+        .path
+        .ok_or_else(|| ProxyError::Parse("Could not parse path".to_owned()))?;
+
+    match req.method {
+        Some("CONNECT") => Ok(Some((
+            ReqType::CONNECT(path.to_string(), http_message::owned_headers(req.headers)),
+            head_len,
+        ))),
+
+        Some(m) => {
+            let headers = http_message::owned_headers(req.headers);
+            let framing = http_message::request_framing(&headers)?;
+
+            Ok(Some((
+                ReqType::HTTP(HttpRequest {
+                    method: m.to_string(),
+                    target: path.to_string(),
+                    version: req.version.unwrap_or(1),
+                    headers,
+                    framing,
+                }),
+                head_len,
+            )))
         }
+
+        None => Err(ProxyError::Parse("Could not parse request".to_owned())),
     }
 }
 
-fn get_req_type(stream: &mut TcpStream) -> Result<ReqType> {
-    let mut buf = [0u8; 4096];
-    let read_bytes = read_from_tcpstream(stream, &mut buf)?;
+/// Read and parse the request from the client. The head may arrive in any
+/// number of segments, so reads of up to `buf_size` bytes are accumulated
+/// until it is complete or grows past `max_head` bytes. `pending` holds bytes
+/// already read from the client, such as a pipelined request that arrived
+/// along with the previous one. Any bytes past the request head are returned
+/// alongside it.
+async fn get_req_type<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    pending: Vec<u8>,
+    buf_size: usize,
+    max_head: usize,
+) -> Result<(ReqType, Vec<u8>)> {
+    let mut buf = pending;
+    let mut chunk = vec![0u8; buf_size];
+
+    loop {
+        if !buf.is_empty() {
+            match determine_request(&buf)? {
+                Some((_, head_len)) if head_len > max_head => return Err(ProxyError::HeaderTooLarge),
+                Some((req_type, head_len)) => return Ok((req_type, buf.split_off(head_len))),
+                None if buf.len() > max_head => return Err(ProxyError::HeaderTooLarge),
+                None => {}
+            }
+        }
 
-    determine_request(&mut buf[0..read_bytes])
+        let read_bytes = read_from_tcpstream(stream, &mut chunk).await?;
+        buf.extend_from_slice(&chunk[0..read_bytes]);
+    }
 }
 
 /// Wrappers to read from and write to asyn TcpStream
 /// Wrapper to write to a stream
-fn write_to_tcpstream(stream: &mut TcpStream, buf: &[u8]) -> Result<usize> {
-    match stream.write_all(buf) {
-        Ok(()) => match stream.flush() {
+async fn write_to_tcpstream<W: AsyncWrite + Unpin>(stream: &mut W, buf: &[u8]) -> Result<usize> {
+    match stream.write_all(buf).await {
+        Ok(()) => match stream.flush().await {
             Ok(_) => Ok(buf.len()),
             Err(e) => Err(ProxyError::IO(format!("While flushing {:?}", e))),
         },
-        Err(ref e) if e.kind() == WouldBlock => Err(ProxyError::IOBlocked),
         Err(e) => Err(ProxyError::IO(format!("While writing {:?}", e))),
     }
 }
 
 /// Wrapper to read from a stream
-fn read_from_tcpstream(stream: &mut TcpStream, buf: &mut [u8]) -> Result<usize> {
-    match stream.read(buf) {
+async fn read_from_tcpstream<R: AsyncRead + Unpin>(stream: &mut R, buf: &mut [u8]) -> Result<usize> {
+    match stream.read(buf).await {
+        Ok(0) => Err(ProxyError::StreamClosed),
+        Ok(n) => Ok(n),
+        Err(e) => Err(ProxyError::IO(format!("While reading {:?}", e))),
+    }
+}
+
+/// Wrapper for a single read from a socket that doesn't wait for it to be readable
+fn try_read_from_tcpstream(stream: &TcpStream, buf: &mut [u8]) -> Result<usize> {
+    match stream.try_read(buf) {
         Ok(0) => Err(ProxyError::StreamClosed),
         Ok(n) => Ok(n),
 
@@ -93,170 +184,1162 @@ fn read_from_tcpstream(stream: &mut TcpStream, buf: &mut [u8]) -> Result<usize>
     }
 }
 
-/// Forward data back and forth between source and target using the TunnelBuffer struct
-struct TunnelBuffer(usize, [u8; 10240]);
+/// Wrapper for a single write to a socket that doesn't wait for it to be writable, and may only take part of the
+/// buffer
+fn try_write_to_tcpstream(stream: &TcpStream, buf: &[u8]) -> Result<usize> {
+    match stream.try_write(buf) {
+        Ok(0) => Err(ProxyError::StreamClosed),
+        Ok(n) => Ok(n),
+
+        Err(ref e) if e.kind() == WouldBlock => Err(ProxyError::IOBlocked),
+        Err(e) => Err(ProxyError::IO(format!("While writing {:?}", e))),
+    }
+}
+
+/// One direction of a tunnel, holding data read from its source that hasn't been written to its destination yet
+trait TunnelDirection {
+    /// Bytes waiting to be written to the destination
+    fn pending(&self) -> usize;
+
+    /// Move data from src to dst without blocking. Returns the number of bytes written to dst, which is 0 if either
+    /// socket would have blocked, or StreamClosed once src has reached EOF and everything read from it has been sent.
+    fn send(&mut self, src: &TcpStream, dst: &TcpStream) -> Result<usize>;
+}
+
+/// Data read from one side of a tunnel that hasn't been written to the other side yet. The first field is how many
+/// bytes of the buffer are pending.
+struct TunnelBuffer(usize, Vec<u8>);
+
+impl TunnelDirection for TunnelBuffer {
+    fn pending(&self) -> usize {
+        self.0
+    }
+
+    fn send(&mut self, src: &TcpStream, dst: &TcpStream) -> Result<usize> {
+        tunnel_through(self, src, dst)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl TunnelDirection for splice::Pipe {
+    fn pending(&self) -> usize {
+        splice::Pipe::pending(self)
+    }
 
-fn convert_tunnel_buffer(buf: TunnelBuffer) -> Result<String> {
-    match str::from_utf8(&buf.1[0..buf.0]) {
-        Ok(res) => Ok(res.to_owned()),
-        _ => Err(ProxyError::Parse(
-            "Could not parse UTF-8 string.".to_owned(),
-        )),
+    fn send(&mut self, src: &TcpStream, dst: &TcpStream) -> Result<usize> {
+        splice_through(self, src, dst)
     }
 }
 
+/// Move data from src to dst through the tunnel buffer without blocking. Returns the number of bytes written to dst,
+/// which is 0 if either socket would have blocked. src is only read once the buffer is empty, so there is nothing left
+/// to send by the time its EOF shows up as StreamClosed.
 fn tunnel_through(
     tunnel_buf: &mut TunnelBuffer,
-    src: &mut TcpStream,
-    dst: &mut TcpStream,
+    src: &TcpStream,
+    dst: &TcpStream,
 ) -> Result<usize> {
-    let mut result = Ok(0usize);
-
     // if tunnel buffer is empty, read from src
     if tunnel_buf.0 == 0 {
-        match read_from_tcpstream(src, &mut tunnel_buf.1) {
-            Ok(0) => {
-                result = Err(ProxyError::StreamClosed);
-            }
+        match try_read_from_tcpstream(src, &mut tunnel_buf.1) {
             Ok(n) => {
                 tunnel_buf.0 = n;
             }
-            Err(ProxyError::IOBlocked) => {}
-            Err(_) => {
-                result = Err(ProxyError::StreamClosed);
-            }
+            Err(ProxyError::IOBlocked) => return Ok(0),
+            Err(e) => return Err(e),
         };
     }
 
-    // if tunnel buffer was modified, write it to dst
-    if tunnel_buf.0 > 0 {
-        match write_to_tcpstream(dst, &mut tunnel_buf.1[0..tunnel_buf.0]) {
-            Ok(0) => {
-                result = Err(ProxyError::StreamClosed);
-            }
-            Ok(n) => {
-                logging::event_log(
-                    Event::DataTransfer,
-                    &format!(
-                        "{} of {} bytes sent from {} to {}",
-                        n,
-                        tunnel_buf.0,
-                        src.peer_addr()
-                            .map_err(|_| ProxyError::Other("".to_owned()))?
-                            .ip(),
-                        dst.peer_addr()
-                            .map_err(|_| ProxyError::Other("".to_owned()))?
-                            .ip()
-                    ),
-                );
+    // write as much of the tunnel buffer as dst takes, the rest waits until dst is writable again
+    match try_write_to_tcpstream(dst, &tunnel_buf.1[0..tunnel_buf.0]) {
+        Ok(n) => {
+            tunnel_buf.1.copy_within(n..tunnel_buf.0, 0);
+            tunnel_buf.0 -= n;
+            Ok(n)
+        }
+        Err(ProxyError::IOBlocked) => Ok(0),
+        Err(e) => Err(e),
+    }
+}
 
-                result = Ok(n);
-                tunnel_buf.0 = 0;
+/// Move data from src to dst through a pipe with splice(2), the same way tunnel_through does with a buffer
+#[cfg(target_os = "linux")]
+fn splice_through(pipe: &mut splice::Pipe, src: &TcpStream, dst: &TcpStream) -> Result<usize> {
+    // if the pipe is empty, splice from src
+    if pipe.pending() == 0 {
+        match pipe.fill(src) {
+            Ok(0) => return Err(ProxyError::StreamClosed),
+            Ok(_) => {}
+            Err(ref e) if e.kind() == WouldBlock => return Ok(0),
+            Err(e) => return Err(ProxyError::IO(format!("While splicing {:?}", e))),
+        }
+    }
+
+    match pipe.drain(dst) {
+        Ok(n) => Ok(n),
+        Err(ref e) if e.kind() == WouldBlock => Ok(0),
+        Err(e) => Err(ProxyError::IO(format!("While splicing {:?}", e))),
+    }
+}
+
+/// Address of the other end of a tunnel socket, for the event log. Once both ends of a connection have been closed its
+/// peer address is gone, which mustn't fail the tunnel.
+pub fn peer_ip(stream: &TcpStream) -> String {
+    match stream.peer_addr() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => "closed peer".to_owned(),
+    }
+}
+
+/// Forward data back and forth between source and target until either side closes the connection. Data is moved with
+/// splice(2) if it's enabled and the pipes can be created, otherwise it goes through userspace buffers. The connection
+/// gives up its place among limits.workers for the life of the tunnel, which the caller holds a TunnelSlot for.
+///
+/// Returns how many bytes were moved along with how the tunnel ended, so the count isn't lost when it ends on an error.
+pub async fn tunnel(s_stream: &mut TcpStream, t_stream: &mut TcpStream, config: &Config) -> (usize, Result<()>) {
+    admission::release();
+
+    let buffers = &config.buffers;
+    let timeouts = &config.timeouts;
+
+    #[cfg(target_os = "linux")]
+    if buffers.splice {
+        match (splice::Pipe::new(), splice::Pipe::new()) {
+            (Ok(source_pipe), Ok(target_pipe)) => {
+                return tunnel_with(s_stream, t_stream, source_pipe, target_pipe, timeouts).await
             }
-            Err(ProxyError::IOBlocked) => {}
-            Err(_) => {
-                result = Err(ProxyError::StreamClosed);
+            (Err(e), _) | (_, Err(e)) => logging::event_log(
+                Event::DataTransfer,
+                &format!("Could not create splice pipes, tunnelling through buffers: {}", e),
+            ),
+        }
+    }
+
+    let source_buf = TunnelBuffer(0usize, vec![0; buffers.tunnel]);
+    let target_buf = TunnelBuffer(0usize, vec![0; buffers.tunnel]);
+    tunnel_with(s_stream, t_stream, source_buf, target_buf, timeouts).await
+}
+
+/// Run a tunnel over the given pair of directions until both have finished. When one side closes its end, whatever it
+/// sent is flushed and only the write half toward its peer is shut down, so the peer sees EOF while the reverse
+/// direction stays open for its reply. Any other error ends the tunnel in both directions, as does going without
+/// traffic for the idle timeout or reaching the maximum lifetime.
+///
+/// The task sleeps until a socket it's waiting on is ready, so an idle tunnel doesn't use any CPU. Everything in flight
+/// lives in the tunnel buffers rather than in the futures being waited on, so the task can be cancelled at any point.
+async fn tunnel_with<D: TunnelDirection>(
+    s_stream: &mut TcpStream,
+    t_stream: &mut TcpStream,
+    mut source_buf: D,
+    mut target_buf: D,
+    timeouts: &TimeoutsConfig,
+) -> (usize, Result<()>) {
+    let mut total_bytes = 0usize;
+    let mut source_open = true;
+    let mut target_open = true;
+    let deadline = Instant::now() + timeouts.tunnel_lifetime();
+
+    let result = loop {
+        if !(source_open || target_open) {
+            break Ok(());
+        }
+        if Instant::now() >= deadline {
+            break Err(ProxyError::TunnelLifetimeExceeded);
+        }
+
+        let sent = match (
+            send_or_close(&mut source_buf, &mut source_open, s_stream, t_stream).await,
+            send_or_close(&mut target_buf, &mut target_open, t_stream, s_stream).await,
+        ) {
+            (Ok(a), Ok(b)) => a + b,
+            (Err(e), _) | (_, Err(e)) => break Err(e),
+        };
+        total_bytes += sent;
+
+        // Only wait once neither direction can make progress. Each open direction is then blocked either reading its
+        // source, if its buffer is empty, or writing its destination.
+        if sent == 0 && (source_open || target_open) {
+            let result = tokio::select! {
+                r = ready(&source_buf, source_open, s_stream, t_stream) => r,
+                r = ready(&target_buf, target_open, t_stream, s_stream) => r,
+                _ = time::sleep(timeouts.tunnel_idle()) => break Err(ProxyError::TunnelIdleTimeout),
+                _ = time::sleep_until(deadline) => break Err(ProxyError::TunnelLifetimeExceeded),
+            };
+            if let Err(e) = result {
+                break Err(ProxyError::IO(format!("While waiting on tunnel {:?}", e)));
             }
+        }
+    };
+
+    log_expired_tunnel(&result, timeouts, &peer_ip(s_stream), &peer_ip(t_stream), total_bytes);
+
+    let _ = s_stream.shutdown().await;
+    let _ = t_stream.shutdown().await;
+
+    (total_bytes, result)
+}
+
+/// Note in the event log how many bytes a tunnel exchanged in total. `session` names the protocol of an upgraded
+/// connection.
+pub fn log_tunnel_total(total: usize, src_addr: IpAddr, dst_addr: impl Display, session: Option<&str>) {
+    let msg = format!("Total {} bytes exchanged between {} and {}", total, src_addr, dst_addr);
+    match session {
+        Some(protocol) => logging::event_log(Event::DataTransfer, &format!("{} in the {} session", msg, protocol)),
+        None => logging::event_log(Event::DataTransfer, &msg),
+    }
+}
+
+/// Note in the event log that a tunnel was closed because it timed out, if that's how it ended
+pub fn log_expired_tunnel(result: &Result<()>, timeouts: &TimeoutsConfig, source: &str, target: &str, total_bytes: usize) {
+    let reason = match result {
+        Err(ProxyError::TunnelIdleTimeout) => format!("{}s without traffic", timeouts.tunnel_idle_secs),
+        Err(ProxyError::TunnelLifetimeExceeded) => format!("its {}s lifetime", timeouts.tunnel_lifetime_secs),
+        _ => return,
+    };
+    logging::event_log(
+        Event::Timeout,
+        &format!(
+            "Tunnel between {} and {} closed after {}, {} bytes exchanged",
+            source, target, reason, total_bytes
+        ),
+    );
+}
+
+/// Send what one direction of a tunnel can. Once its source has reached EOF and everything has been sent, the direction
+/// is marked closed and the write half of its destination is shut down to pass the EOF on.
+async fn send_or_close(
+    tunnel_buf: &mut impl TunnelDirection,
+    open: &mut bool,
+    src: &TcpStream,
+    dst: &mut TcpStream,
+) -> Result<usize> {
+    if !*open {
+        return Ok(0);
+    }
+
+    match tunnel_buf.send(src, dst) {
+        Err(ProxyError::StreamClosed) => {
+            *open = false;
+            dst.shutdown()
+                .await
+                .map_err(|e| ProxyError::IO(format!("While shutting down {:?}", e)))?;
+            Ok(0)
+        }
+        result => result,
+    }
+}
+
+/// Wait until the direction from src to dst can make progress. A closed direction never can.
+async fn ready(
+    tunnel_buf: &impl TunnelDirection,
+    open: bool,
+    src: &TcpStream,
+    dst: &TcpStream,
+) -> std::io::Result<()> {
+    if !open {
+        return future::pending().await;
+    }
+
+    match tunnel_buf.pending() {
+        0 => src.readable().await,
+        _ => dst.writable().await,
+    }
+}
+
+/// The response the proxy sends for an error, before it is put in the form of the client's HTTP version. The error is
+/// named in a Proxy-Status header (RFC 9209) so clients and tools can tell why the request failed.
+pub struct ErrorResponse {
+    pub reply: ErrorReply,
+    /// Headers other than Content-Type and the framing ones
+    pub headers: Vec<Header>,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl ErrorResponse {
+    /// The response for an error that has one. The body is a short plain text explanation.
+    pub fn new(e: &ProxyError) -> Option<ErrorResponse> {
+        let reply = e.reply()?;
+        let body = format!("{} {}\n{}\n", reply.status, reply.reason, reply.details);
+
+        Some(ErrorResponse::with_body(e, reply, "text/plain; charset=utf-8", body))
+    }
+
+    /// The response for a request to `target` that the firewall denied, carrying the block page. Other errors get
+    /// their plain response. The request ID on the page is logged along with the denied host so it can be looked up.
+    pub fn block_page(
+        block_page: &SharedBlockPage,
+        target: &str,
+        accept: Option<&str>,
+        e: &ProxyError,
+    ) -> Option<ErrorResponse> {
+        let reason = match e {
+            ProxyError::WhiteListDeny => "Your address is not allowed to use this proxy",
+            ProxyError::BlackListDeny => "The site is blacklisted",
+            ProxyError::PolicyDeny => "The request is not allowed by the access policy",
+            _ => return ErrorResponse::new(e),
+        };
+        let reply = e.reply()?;
+
+        let host = origin_target(target)
+            .map(|(host, _, _)| host)
+            .unwrap_or_else(|_| target.to_owned());
+        let request_id = block_page::request_id();
+        let denial = Denial {
+            host: &host,
+            reason,
+            request_id: &request_id,
         };
+        let (content_type, body) = block_page.page().render(&denial, accept);
+
+        logging::event_log(
+            Event::Connection,
+            &format!("Block page {} served for {}", request_id, host),
+        );
+        Some(ErrorResponse::with_body(e, reply, content_type, body))
+    }
+
+    fn with_body(e: &ProxyError, reply: ErrorReply, content_type: &'static str, body: String) -> ErrorResponse {
+        let proxy_status = format!(
+            "shallot; error={}; details={}",
+            reply.error_type,
+            sf_string(&reply.details)
+        );
+        let mut headers = vec![Header::new("Proxy-Status", proxy_status.as_bytes())];
+        if let ProxyError::ProxyAuthRequired = e {
+            headers.push(Header::new("Proxy-Authenticate", PROXY_AUTHENTICATE.as_bytes()));
+        }
+
+        ErrorResponse {
+            reply,
+            headers,
+            content_type,
+            body,
+        }
     }
 
-    result
+    /// The response as HTTP/1.1, which closes the connection
+    fn to_http1(&self) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 {} {}\r\n", self.reply.status, self.reply.reason).into_bytes();
+        for header in &self.headers {
+            response.extend_from_slice(header.name.as_bytes());
+            response.extend_from_slice(b": ");
+            response.extend_from_slice(&header.value);
+            response.extend_from_slice(b"\r\n");
+        }
+        response.extend_from_slice(
+            format!(
+                "Content-Type: {}\r\n\
+                Content-Length: {}\r\n\
+                Connection: close\r\n\r\n{}",
+                self.content_type,
+                self.body.len(),
+                self.body
+            )
+            .as_bytes(),
+        );
+
+        response
+    }
 }
 
-fn tunnel(s_stream: &mut TcpStream, t_stream: &mut TcpStream) -> usize {
-    let mut total_bytes = 0usize;
+/// Quote text as a structured field string (RFC 8941), which only allows printable ASCII
+fn sf_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars().filter(|c| (' '..='~').contains(c)) {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Answer the client with the response for an error, if it has one, and hand the error back to be returned
+async fn reply_error(stream: &mut TcpStream, e: ProxyError) -> ProxyError {
+    if let Some(response) = ErrorResponse::new(&e) {
+        let _ = write_to_tcpstream(stream, &response.to_http1()).await;
+    }
+    e
+}
+
+/// Answer a plain HTTP request denied by the firewall with the block page, and hand the error back to be returned
+async fn reply_block_page(
+    stream: &mut TcpStream,
+    block_page: &SharedBlockPage,
+    req: &HttpRequest,
+    e: ProxyError,
+) -> ProxyError {
+    let accept = http_message::find_header(&req.headers, "Accept").map(|h| h.value_str());
+
+    if let Some(response) = ErrorResponse::block_page(block_page, &req.target, accept, &e) {
+        let _ = write_to_tcpstream(stream, &response.to_http1()).await;
+    }
+    e
+}
+
+/// Turn a client away with 503 because too many connections are being handled. This runs on the accepting task, so
+/// it doesn't wait for the socket: the response fits in the socket buffer, and a client that isn't reading must not
+/// hold up accepting other connections.
+pub fn reject_overloaded(stream: &TcpStream, retry_after_secs: u64) {
+    if let Some(mut response) = ErrorResponse::new(&ProxyError::Overloaded) {
+        let retry_after = retry_after_secs.to_string();
+        response.headers.push(Header::new("Retry-After", retry_after.as_bytes()));
+        let _ = stream.try_write(&response.to_http1());
+    }
+}
+
+/// Check the Proxy-Authorization credentials of a request. Fails with
+/// ProxyAuthRequired, answered with a 407 challenge, if the proxy requires
+/// authentication and the credentials are missing or wrong. On success the
+/// user is attached to every event logged for the rest of the connection.
+async fn check_credentials(fwall: &Firewall, src_addr: &IpAddr, headers: &[Header]) -> Result<Option<String>> {
+    let credentials = http_message::find_header(headers, "Proxy-Authorization").map(|h| h.value_str());
+
+    check_auth_result(fwall.authenticate(credentials).await, src_addr, credentials.is_some())
+}
+
+/// Act on the outcome of authenticating a client. The user is attached to every event logged for the rest of the
+/// connection, and a denial is logged. `given` is whether the client sent credentials at all.
+pub fn check_auth_result(result: AuthResult, src_addr: &IpAddr, given: bool) -> Result<Option<String>> {
+    match result {
+        AuthResult::Disabled => Ok(None),
+        AuthResult::User(user) => {
+            logging::set_user(Some(user.clone()));
+            Ok(Some(user))
+        }
+        AuthResult::Denied => {
+            logging::event_log(
+                Event::AuthDeny,
+                &format!(
+                    "{} from {}",
+                    match given {
+                        true => "Invalid credentials",
+                        false => "No credentials",
+                    },
+                    src_addr
+                ),
+            );
+            Err(ProxyError::ProxyAuthRequired)
+        }
+    }
+}
+
+/// Check the source of a request against the whitelist. Fails with
+/// WhiteListDeny if the client is not allowed to use the proxy.
+pub fn check_source(fwall: &Firewall, src_addr: &IpAddr) -> Result<()> {
+    if !fwall.in_whitelist(&src_addr.to_string()) {
+        logging::event_log(
+            Event::WhiteListDeny,
+            &format!("{} not in whitelist", src_addr),
+        );
+        return Err(ProxyError::WhiteListDeny);
+    }
+
+    Ok(())
+}
+
+/// Check the destination hostnames and every address they resolved to
+/// against the blacklist. This happens before any connection is made, so a
+/// blacklisted destination never hears from the proxy.
+fn check_destination(
+    fwall: &Firewall,
+    hosts: &[String],
+    addrs: &[SocketAddr],
+) -> Result<()> {
+    let denied = hosts
+        .iter()
+        .cloned()
+        .chain(addrs.iter().map(|a| a.ip().to_string()))
+        .find(|dst| fwall.in_blacklist(dst));
+
+    if let Some(dst) = denied {
+        logging::event_log(
+            Event::BlackListDeny,
+            &format!("{} in blacklist", dst),
+        );
+        return Err(ProxyError::BlackListDeny);
+    }
+
+    Ok(())
+}
+
+/// Fail with LoopDetected if a request already went through this proxy, as
+/// told by its pseudonym in one of the request's Via entries
+fn check_via(headers: &[Header], pseudonym: &str, src_addr: &IpAddr) -> Result<()> {
+    // Each entry is the protocol, the pseudonym and an optional comment
+    let looped = http_message::header_tokens(headers, "Via")
+        .iter()
+        .any(|entry| entry.split_whitespace().nth(1) == Some(&pseudonym.to_ascii_lowercase()));
+
+    if looped {
+        logging::event_log(
+            Event::SuspiciousActivity,
+            &format!("Request from {} already passed through {}", src_addr, pseudonym),
+        );
+        return Err(ProxyError::LoopDetected);
+    }
+
+    Ok(())
+}
+
+/// Fail with LoopDetected if any address a destination resolved to is one
+/// the proxy itself listens on. Listeners bound to an unspecified address
+/// accept connections on every local address, of which the loopback ones
+/// and the one the client connected to are known. Connecting to an
+/// unspecified address reaches the loopback listeners.
+fn check_own_address(addrs: &[SocketAddr], listeners: &[SocketAddr], local_ip: IpAddr, src_addr: &IpAddr) -> Result<()> {
+    let local_ip = local_ip.to_canonical();
+    let own = addrs.iter().find(|addr| {
+        let ip = addr.ip().to_canonical();
+        listeners.iter().any(|listener| {
+            let bound = listener.ip().to_canonical();
+            listener.port() == addr.port()
+                && (bound == ip
+                    || (bound.is_unspecified() && (ip.is_loopback() || ip.is_unspecified() || ip == local_ip))
+                    || (ip.is_unspecified() && bound.is_loopback()))
+        })
+    });
+
+    if let Some(addr) = own {
+        logging::event_log(
+            Event::SuspiciousActivity,
+            &format!("Request from {} for {} would loop back to the proxy", src_addr, addr),
+        );
+        return Err(ProxyError::LoopDetected);
+    }
+
+    Ok(())
+}
+
+/// Evaluate the access policy for a request and fail with PolicyDeny if it
+/// is denied. Matching log-only rules are noted in the event log.
+fn check_policy(
+    fwall: &Firewall,
+    client: &Client<'_>,
+    hosts: &[String],
+    addrs: &[SocketAddr],
+    port: u16,
+    upgrades: &[String],
+) -> Result<()> {
+    let addrs: Vec<IpAddr> = addrs.iter().map(|a| a.ip()).collect();
+    let dst = Destination {
+        hosts,
+        addrs: &addrs,
+        port,
+        upgrades,
+    };
+    let upgrading = match upgrades.is_empty() {
+        true => String::new(),
+        false => format!(" upgrading to {}", upgrades.join(", ")),
+    };
+
+    let verdict = match fwall.evaluate_policy(client, &dst) {
+        Some(verdict) => verdict,
+        None => return Ok(()),
+    };
+
+    for line in &verdict.logged {
+        logging::event_log(
+            Event::Connection,
+            &format!(
+                "{} to {}:{}{} matched policy log rule on line {}",
+                client.addr,
+                hosts.join(", "),
+                port,
+                upgrading,
+                line
+            ),
+        );
+    }
+
+    if verdict.action == Action::Deny {
+        logging::event_log(
+            Event::PolicyDeny,
+            &format!(
+                "{} to {}:{}{} denied by {}",
+                client.addr,
+                hosts.join(", "),
+                port,
+                upgrading,
+                match verdict.line {
+                    Some(line) => format!("policy rule on line {}", line),
+                    None => "default policy".to_owned(),
+                }
+            ),
+        );
+        return Err(ProxyError::PolicyDeny);
+    }
+
+    Ok(())
+}
+
+/// Check a request against the access policy if there is one, otherwise
+/// against the whitelist and blacklist. Runs after the client has been
+/// authenticated and the destination resolved, but before connecting to it.
+/// Only policy rules can single out requests to upgrade the connection.
+fn check_access(
+    fwall: &Firewall,
+    client: &Client<'_>,
+    hosts: &[String],
+    addrs: &[SocketAddr],
+    port: u16,
+    upgrades: &[String],
+) -> Result<()> {
+    match fwall.policy_enabled() {
+        true => check_policy(fwall, client, hosts, addrs, port, upgrades),
+        false => check_destination(fwall, hosts, addrs),
+    }
+}
+
+/// Split a CONNECT authority into host and port. IPv6 literals are expected
+/// in brackets, as in [::1]:443.
+fn split_authority(authority: &str) -> Result<(String, u16)> {
+    let (host, port) = authority
+        .rsplit_once(':')
+        .ok_or_else(|| ProxyError::Parse(format!("Missing port in {}", authority)))?;
+
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| ProxyError::Parse(format!("Invalid port in {}", authority)))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    if host.is_empty() {
+        return Err(ProxyError::Parse(format!("Missing host in {}", authority)));
+    }
+
+    Ok((host.to_owned(), port))
+}
+
+/// Hostname named in the Host header of a request, without the port
+fn host_header(req: &HttpRequest) -> Option<String> {
+    let value = http_message::find_header(&req.headers, "Host")?.value_str().trim();
+
+    let host = match value.strip_prefix('[') {
+        Some(v6) => v6.split(']').next().unwrap_or(""),
+        None => value.split(':').next().unwrap_or(""),
+    };
+
+    match host.is_empty() {
+        true => None,
+        false => Some(host.to_owned()),
+    }
+}
+
+/// Split an absolute-form request target into the host and port to connect
+/// to and the origin-form target to send in the forwarded request line.
+fn origin_target(target: &str) -> Result<(String, u16, String)> {
+    let url = Url::parse(target).map_err(|e| ProxyError::Parse(format!("{:?}", e)))?;
+
+    if url.scheme() != "http" {
+        return Err(ProxyError::Parse(format!(
+            "Unsupported scheme {}",
+            url.scheme()
+        )));
+    }
+
+    let host = url
+        .host_str()
+        .ok_or_else(|| ProxyError::Parse("Missing host".to_owned()))?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let mut path = url.path().to_owned();
+    if let Some(q) = url.query() {
+        path.push('?');
+        path.push_str(q);
+    }
+
+    Ok((host.to_owned(), port, path))
+}
+
+/// Drop the hop-by-hop headers of a message, those listed in HOP_BY_HOP and
+/// those its Connection header names, leaving the ones meant for the other
+/// end.
+fn end_to_end_headers(headers: &[Header]) -> Vec<Header> {
+    let named = http_message::header_tokens(headers, "Connection");
+
+    headers
+        .iter()
+        .filter(|h| {
+            let is = |name: &&str| name.eq_ignore_ascii_case(&h.name);
+            FRAMING.iter().any(is)
+                || !(HOP_BY_HOP.iter().any(is) || named.contains(&h.name.to_ascii_lowercase()))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Replace every header with the given name by a single one listing their
+/// values followed by `value`
+fn append_header(headers: &mut Vec<Header>, name: &str, value: &str) {
+    let mut values: Vec<&[u8]> = headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_slice())
+        .collect();
+    values.push(value.as_bytes());
+
+    let combined = Header::new(name, &values.join(&b", "[..]));
+    headers.retain(|h| !h.name.eq_ignore_ascii_case(name));
+    headers.push(combined);
+}
+
+/// Add what the listener's anonymity level reveals about the proxy and the
+/// client to the headers of a forwarded request. Headers added by proxies
+/// before this one are dropped too when they would reveal what it hides.
+fn add_forwarding_headers(
+    headers: &mut Vec<Header>,
+    protocol: &str,
+    src_addr: IpAddr,
+    anonymity: Anonymity,
+    pseudonym: &str,
+) {
+    if anonymity != Anonymity::Transparent {
+        headers.retain(|h| {
+            !h.name.eq_ignore_ascii_case("X-Forwarded-For") && !h.name.eq_ignore_ascii_case("Forwarded")
+        });
+    }
+
+    match anonymity {
+        Anonymity::Elite => headers.retain(|h| !h.name.eq_ignore_ascii_case("Via")),
+        _ => append_header(headers, "Via", &format!("{} {}", protocol, pseudonym)),
+    }
+
+    if anonymity == Anonymity::Transparent {
+        let src_addr = src_addr.to_canonical();
+        let node = match src_addr {
+            IpAddr::V4(addr) => addr.to_string(),
+            IpAddr::V6(addr) => format!("\"[{}]\"", addr),
+        };
+        append_header(headers, "X-Forwarded-For", &src_addr.to_string());
+        append_header(headers, "Forwarded", &format!("for={};proto=http", node));
+    }
+}
+
+/// Build the head of the request sent to the origin. The connection to the
+/// origin is only used for one request, so the client's connection options
+/// are dropped along with the other hop-by-hop headers and replaced with
+/// Connection: close. The exception is a request to upgrade the connection,
+/// which the origin has to see to agree to it. Expect is dropped since the
+/// proxy answers 100-continue itself, and so is Content-Length when there is a
+/// Transfer-Encoding, which is what the body is relayed by.
+pub fn build_origin_request(
+    req: &HttpRequest,
+    path: &str,
+    host: &str,
+    port: u16,
+    src_addr: IpAddr,
+    anonymity: Anonymity,
+    pseudonym: &str,
+) -> Vec<u8> {
+    let mut head = format!("{} {} HTTP/1.1\r\n", req.method, path).into_bytes();
+
+    let mut forwarded = end_to_end_headers(&req.headers);
+    forwarded.retain(|h| !h.name.eq_ignore_ascii_case("Expect"));
+    if http_message::find_header(&forwarded, "Transfer-Encoding").is_some() {
+        forwarded.retain(|h| !h.name.eq_ignore_ascii_case("Content-Length"));
+    }
+    add_forwarding_headers(&mut forwarded, &req.protocol(), src_addr, anonymity, pseudonym);
+
+    let upgrade = !req.upgrades().is_empty();
+    if upgrade {
+        forwarded.extend(req.headers.iter().filter(|h| h.name.eq_ignore_ascii_case("Upgrade")).cloned());
+    }
 
-    // Init buffers for tunneling
-    let mut source_buf = TunnelBuffer(0usize, [0; 10240]);
-    let mut target_buf = TunnelBuffer(0usize, [0; 10240]);
+    if http_message::find_header(&forwarded, "Host").is_none() {
+        let host = match host.contains(':') {
+            true => format!("[{}]", host),
+            false => host.to_owned(),
+        };
+        head.extend_from_slice(format!("Host: {}:{}\r\n", host, port).as_bytes());
+    }
+    http_message::write_headers(&mut head, &forwarded);
+    match upgrade {
+        true => head.extend_from_slice(b"Connection: Upgrade\r\n\r\n"),
+        false => head.extend_from_slice(b"Connection: close\r\n\r\n"),
+    }
+
+    head
+}
+
+/// Headers of a response from the origin that are relayed to the client. Its hop-by-hop headers are about the origin's
+/// connection to the proxy, so they are dropped, and the proxy adds itself to Via unless it's elite.
+pub fn relayed_response_headers(resp: &ResponseHead, anonymity: Anonymity, pseudonym: &str) -> Vec<Header> {
+    let mut relayed = end_to_end_headers(&resp.headers);
+    if anonymity != Anonymity::Elite {
+        let version = match resp.raw.starts_with(b"HTTP/1.0") {
+            true => "1.0",
+            false => "1.1",
+        };
+        append_header(&mut relayed, "Via", &format!("{} {}", version, pseudonym));
+    }
+    relayed
+}
 
-    // Set both streams to non blocking
-    s_stream.set_nonblocking(true);
-    t_stream.set_nonblocking(true);
+/// Build the head of the response relayed to the client from the one the
+/// origin sent, telling the client whether its own connection stays open
+fn build_client_response(resp: &ResponseHead, keep_alive: bool, anonymity: Anonymity, pseudonym: &str) -> Vec<u8> {
+    let status_line = resp.raw.split(|&b| b == b'\n').next().unwrap_or(&[]);
+    let status_line = status_line.strip_suffix(b"\r").unwrap_or(status_line);
+    let mut head = status_line.to_vec();
+    head.extend_from_slice(b"\r\n");
 
+    http_message::write_headers(&mut head, &relayed_response_headers(resp, anonymity, pseudonym));
+
+    match keep_alive {
+        true => head.extend_from_slice(b"Connection: keep-alive\r\n\r\n"),
+        false => head.extend_from_slice(b"Connection: close\r\n\r\n"),
+    }
+    head
+}
+
+/// Read the next response head from the origin. Anything that keeps it from being read is an invalid response, which
+/// the client can be told about with 502 as long as nothing has been relayed yet.
+pub async fn read_response<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<ResponseHead> {
+    let head = http_message::read_head(reader).await.map_err(|e| match e {
+        ProxyError::StreamClosed => ProxyError::InvalidResponse("Connection closed by the origin".to_owned()),
+        ProxyError::IO(msg) | ProxyError::Parse(msg) => ProxyError::InvalidResponse(msg),
+        e => e,
+    })?;
+    http_message::parse_response(head).map_err(|e| match e {
+        ProxyError::Parse(msg) => ProxyError::InvalidResponse(msg),
+        e => e,
+    })
+}
+
+/// Outcome of forwarding one request
+#[derive(Debug)]
+struct Forwarded {
+    status: u16,
+    /// Bytes relayed in both directions
+    total: u64,
+    /// Whether the client connection stays open for another request
+    keep_alive: bool,
+    /// The protocol the origin switched the connection to, if it did. Both connections are then tunnelled.
+    upgraded: Option<String>,
+    /// Bytes read from the client past the end of the request body, the start of the next request
+    pending: Vec<u8>,
+}
+
+/// Send a request and its body to the origin and relay the response back to
+/// the client. `body_prefix` holds bytes that were read from the client
+/// together with the request head. The client connection is kept open if the
+/// client asked for it and the end of the response can be told without
+/// closing it. If the origin agrees to upgrade the connection, everything it
+/// sent after its 101 response is relayed too and the request ends there.
+async fn forward_request(
+    stream: &mut TcpStream,
+    t_stream: &mut TcpStream,
+    req: &HttpRequest,
+    head: &[u8],
+    body_prefix: Vec<u8>,
+    anonymity: Anonymity,
+    pseudonym: &str,
+) -> Result<Forwarded> {
+    let (client_r, mut client_w) = stream.split();
+    let (target_r, mut target_w) = t_stream.split();
+
+    write_to_tcpstream(&mut target_w, head).await?;
+    let mut total = head.len() as u64;
+
+    let pending = match req.framing {
+        BodyFraming::NoBody => body_prefix,
+        framing => {
+            if req.expects_continue() {
+                write_to_tcpstream(&mut client_w, HTTP_CONTINUE).await?;
+            }
+
+            let mut body = BufReader::new(Cursor::new(body_prefix).chain(client_r));
+            total += http_message::relay_body(&mut body, &mut target_w, framing).await?;
+
+            // Whatever was read past the body, buffered or not yet taken from the prefix, belongs to the next request
+            let mut pending = body.buffer().to_vec();
+            let (prefix, _) = body.into_inner().into_inner();
+            pending.extend_from_slice(prefix.get_ref().get(prefix.position() as usize..).unwrap_or(&[]));
+            pending
+        }
+    };
+
+    let mut reader = BufReader::new(target_r);
+
+    // Interim 1xx responses are relayed until the final response arrives. Until the final response head has been
+    // relayed, the client can still be answered with 502 if the origin doesn't send a valid one.
     loop {
-        match tunnel_through(&mut source_buf, s_stream, t_stream) {
-            Ok(n) => {
-                total_bytes += n;
+        let resp = read_response(&mut reader).await?;
+
+        if resp.status == 101 {
+            let requested = req.upgrades();
+            if requested.is_empty() {
+                return Err(ProxyError::InvalidResponse("Switching protocols without being asked to".to_owned()));
             }
-            Err(_) => {
-                s_stream.shutdown(Shutdown::Both);
-                t_stream.shutdown(Shutdown::Both);
-                break;
+
+            write_to_tcpstream(&mut client_w, &resp.raw).await?;
+            total += resp.raw.len() as u64;
+
+            // Whatever the origin sent after the 101 is already in the new protocol
+            let switched = reader.buffer().to_vec();
+            if !switched.is_empty() {
+                write_to_tcpstream(&mut client_w, &switched).await?;
+                total += switched.len() as u64;
             }
-        };
 
-        match tunnel_through(&mut target_buf, t_stream, s_stream) {
-            Ok(n) => {
-                total_bytes += n;
+            let protocol = http_message::header_tokens(&resp.headers, "Upgrade")
+                .into_iter()
+                .next()
+                .unwrap_or_else(|| requested.join(", "));
+            return Ok(Forwarded {
+                status: resp.status,
+                total,
+                keep_alive: false,
+                upgraded: Some(protocol),
+                pending,
+            });
+        }
+
+        if (100..200).contains(&resp.status) {
+            write_to_tcpstream(&mut client_w, &resp.raw).await?;
+            total += resp.raw.len() as u64;
+            continue;
+        }
+
+        let framing = http_message::response_framing(&req.method, &resp)?;
+        let keep_alive = req.keep_alive() && framing != BodyFraming::UntilClose;
+
+        let head = build_client_response(&resp, keep_alive, anonymity, pseudonym);
+        write_to_tcpstream(&mut client_w, &head).await?;
+        total += head.len() as u64;
+        total += http_message::relay_body(&mut reader, &mut client_w, framing).await?;
+
+        return Ok(Forwarded {
+            status: resp.status,
+            total,
+            keep_alive,
+            upgraded: None,
+            pending,
+        });
+    }
+}
+
+/// Vet the client of a request. Without a policy file, clients are vetted by the whitelist before anything else. Then
+/// they have to authenticate if the proxy requires it. Returns the authenticated user, if any.
+async fn check_client(fwall: &Firewall, src_addr: &IpAddr, headers: &[Header]) -> Result<Option<String>> {
+    if !fwall.policy_enabled() {
+        check_source(fwall, src_addr)?;
+    }
+    check_credentials(fwall, src_addr, headers).await
+}
+
+/// Vet a CONNECT request and connect to the destination it names
+pub async fn connect_tunnel_target(
+    fwall: &Firewall,
+    config: &Config,
+    src_addr: IpAddr,
+    local_ip: IpAddr,
+    authority: &str,
+    headers: &[Header],
+) -> Result<TcpStream> {
+    let user = check_client(fwall, &src_addr, headers).await?;
+    let (host, port) = split_authority(authority)?;
+
+    connect_tunnel_destination(fwall, config, user.as_deref(), src_addr, local_ip, &host, port).await
+}
+
+/// Vet the destination of a tunnel for a client that has already been vetted, and connect to it
+pub async fn connect_tunnel_destination(
+    fwall: &Firewall,
+    config: &Config,
+    user: Option<&str>,
+    src_addr: IpAddr,
+    local_ip: IpAddr,
+    host: &str,
+    port: u16,
+) -> Result<TcpStream> {
+    let client = Client { user, addr: src_addr };
+
+    let addrs = resolve_target(host, port).await?;
+    check_own_address(&addrs, &config.own_addresses(), local_ip, &src_addr)?;
+    check_access(fwall, &client, &[host.to_owned()], &addrs, port, &[])?;
+
+    get_target_stream(&addrs, config.timeouts.connect()).await
+}
+
+/// Vet a request to be forwarded and connect to its origin. Returns the connection along with the host and port it was
+/// made to and the origin-form target for the forwarded request line.
+pub async fn connect_origin(
+    fwall: &Firewall,
+    config: &Config,
+    src_addr: IpAddr,
+    local_ip: IpAddr,
+    req: &HttpRequest,
+) -> Result<(TcpStream, String, u16, String)> {
+    // Tracing requests through the proxy would echo headers such as cookies back to whatever script sent them
+    if req.method.eq_ignore_ascii_case("TRACE") {
+        return Err(ProxyError::MethodNotAllowed(req.method.clone()));
+    }
+
+    // Checked before the client, since the proxy credentials aren't forwarded and the looped request would be refused
+    // for the lack of them instead
+    check_via(&req.headers, &config.listener.via_pseudonym, &src_addr)?;
+
+    let user = check_client(fwall, &src_addr, &req.headers).await?;
+    let client = Client {
+        user: user.as_deref(),
+        addr: src_addr,
+    };
+
+    let (host, port, path) = origin_target(&req.target)?;
+    let addrs = resolve_target(&host, port).await?;
+    check_own_address(&addrs, &config.own_addresses(), local_ip, &src_addr)?;
+
+    // The Host header is what the origin will act on, so it has to pass too
+    let mut hosts = vec![host.clone()];
+    hosts.extend(host_header(req).filter(|h| !h.eq_ignore_ascii_case(&host)));
+    check_access(fwall, &client, &hosts, &addrs, port, &req.upgrades())?;
+
+    let t_stream = get_target_stream(&addrs, config.timeouts.connect()).await?;
+    Ok((t_stream, host, port, path))
+}
+
+/// Handle the requests on a client connection, one after the other in the order they were sent. Each request is vetted
+/// and logged on its own. Errors that happen before anything has been relayed to the client are answered with an error
+/// response naming them, after which the connection is closed.
+///
+/// Clients that know the proxy speaks HTTP/2 start with its connection preface instead of a request, and the connection
+/// is then handed over to the HTTP/2 server.
+pub async fn process_connection(
+    stream: &mut TcpStream,
+    firewall: &Arc<SharedFirewall>,
+    block_page: &Arc<SharedBlockPage>,
+    config: &Arc<Config>,
+    anonymity: Anonymity,
+) -> Result<()> {
+    let src_addr = stream
+        .peer_addr()
+        .map_err(|e| ProxyError::IO(format!("While getting the socket address {:?}", e)))?
+        .ip();
+
+    if config.http2.h2c {
+        let timeout = config.timeouts.request_header();
+        match http2::has_preface(stream, timeout).await {
+            Ok(true) => {
+                let local_ip = stream
+                    .local_addr()
+                    .map_err(|e| ProxyError::IO(format!("While getting the socket address {:?}", e)))?
+                    .ip();
+                return http2::serve_connection(stream, firewall, block_page, config, anonymity, src_addr, local_ip)
+                    .await;
             }
-            Err(_) => {
-                s_stream.shutdown(Shutdown::Both);
-                t_stream.shutdown(Shutdown::Both);
-                break;
+            Ok(false) => {}
+            Err(e) => {
+                logging::event_log(
+                    Event::Timeout,
+                    &format!("No request from {} within {}s", src_addr, timeout.as_secs()),
+                );
+                return Err(reply_error(stream, e).await);
             }
+        }
+    }
+
+    let mut pending = match process_request(stream, firewall, block_page, config, anonymity, src_addr, vec![]).await? {
+        Some(pending) => pending,
+        None => return Ok(()),
+    };
+    let mut served = 1;
+
+    loop {
+        // Nothing from the client yet, so it may have finished with the connection
+        if pending.is_empty() {
+            match time::timeout(config.timeouts.keep_alive(), stream.readable()).await {
+                Ok(ready) => ready.map_err(|e| ProxyError::IO(format!("While waiting {:?}", e)))?,
+                Err(_) => break,
+            }
+        }
+
+        pending = match process_request(stream, firewall, block_page, config, anonymity, src_addr, pending).await {
+            Ok(Some(pending)) => pending,
+            Ok(None) => return Ok(()),
+            Err(ProxyError::StreamClosed) => break,
+            Err(e) => return Err(e),
         };
+        served += 1;
     }
 
-    total_bytes
+    logging::event_log(
+        Event::Connection,
+        &format!("Connection from {} closed after {} requests", src_addr, served),
+    );
+    let _res = stream.shutdown().await;
+    Ok(())
 }
 
-pub fn process_connection(stream: &mut TcpStream, fwall: Arc<Mutex<Firewall>>) -> Result<()> {
-    let mut _fwall = fwall.lock().unwrap();
+/// Handle the next request on a client connection. `pending` holds bytes of it that have already been read. Returns the
+/// bytes read past the end of the request if the connection stays open for another one.
+async fn process_request(
+    stream: &mut TcpStream,
+    firewall: &SharedFirewall,
+    block_page: &SharedBlockPage,
+    config: &Config,
+    anonymity: Anonymity,
+    src_addr: IpAddr,
+    pending: Vec<u8>,
+) -> Result<Option<Vec<u8>>> {
+    logging::set_user(None);
 
-    let src_addr = stream
-        .peer_addr()
-        .map_err(|e| ProxyError::Other(format!("{:?}", e)))?
+    let local_ip = stream
+        .local_addr()
+        .map_err(|e| ProxyError::IO(format!("While getting the socket address {:?}", e)))?
         .ip();
 
-    let req_type = get_req_type(stream);
+    let timeout = config.timeouts.request_header();
+    let req_type = match time::timeout(
+        timeout,
+        get_req_type(stream, pending, config.buffers.request, config.buffers.max_request_head),
+    )
+    .await
+    {
+        Ok(req_type) => req_type,
+        Err(_) => {
+            logging::event_log(
+                Event::Timeout,
+                &format!("No request from {} within {}s", src_addr, timeout.as_secs()),
+            );
+            return Err(reply_error(stream, ProxyError::RequestTimeout).await);
+        }
+    };
+
+    // The whole request is checked against the same snapshot, even if the lists are reloaded meanwhile
+    let fwall = firewall.snapshot();
 
     match req_type {
-        Ok(ReqType::CONNECT(p)) => {
+        Ok((ReqType::CONNECT(p, headers), early_data)) => {
             logging::event_log(
                 Event::Connection,
                 &format!("CONNECT request for {} from {}", p, src_addr),
             );
 
-            let mut t_stream = get_target_stream(&p)?;
+            let _slot = match admission::tunnel_slot(&src_addr) {
+                Ok(slot) => slot,
+                Err(e) => return Err(reply_error(stream, e).await),
+            };
+            let mut t_stream = match connect_tunnel_target(&fwall, config, src_addr, local_ip, &p, &headers).await {
+                Ok(t_stream) => t_stream,
+                Err(e) => return Err(reply_error(stream, e).await),
+            };
             let dst_addr = t_stream
                 .peer_addr()
-                .map_err(|e| ProxyError::Other(format!("{:?}", e)))?
+                .map_err(|e| ProxyError::IO(format!("While getting the socket address {:?}", e)))?
                 .ip();
 
-            match _fwall.in_whitelist(&src_addr.to_string()) {
-                true => match _fwall.in_blacklist(&dst_addr.to_string()) {
-                    false => {
-                        logging::event_log(
-                            Event::ProxyServer,
-                            &format!("{} and {} verified", src_addr, dst_addr),
-                        );
-                    }
-
-                    true => {
-                        logging::event_log(
-                            Event::BlackListDeny,
-                            &format!("{} in blacklist", dst_addr),
-                        );
-                        let _res = write_to_tcpstream(stream, HTTP_NOT_AUTH)?;
-                        return Err(ProxyError::BlackListDeny);
-                    }
-                },
-
-                false => {
-                    logging::event_log(
-                        Event::WhiteListDeny,
-                        &format!("{} not in whitelist", src_addr),
-                    );
-                    let _res = write_to_tcpstream(stream, HTTP_NOT_AUTH)?;
-                    return Err(ProxyError::WhiteListDeny);
-                }
-            };
-
-            std::mem::drop(_fwall);
+            logging::event_log(
+                Event::ProxyServer,
+                &format!("{} and {} verified", src_addr, dst_addr),
+            );
 
             // Respond with 200 OK
-            let _res = write_to_tcpstream(stream, HTTP_OK)?;
+            let _res = write_to_tcpstream(stream, HTTP_OK).await?;
 
             logging::event_log(
                 Event::Connection,
@@ -266,35 +1349,480 @@ pub fn process_connection(stream: &mut TcpStream, fwall: Arc<Mutex<Firewall>>) -
                 ),
             );
 
-            let n = tunnel(stream, &mut t_stream);
+            // Clients may start talking to the destination, with a TLS handshake say, without waiting for the 200
+            if !early_data.is_empty() {
+                write_to_tcpstream(&mut t_stream, &early_data).await?;
+            }
+
+            let (n, result) = tunnel(stream, &mut t_stream, config).await;
+            log_tunnel_total(early_data.len() + n, src_addr, dst_addr, None);
+            result.map(|()| None)
+        }
+
+        Ok((ReqType::HTTP(req), body_prefix)) => {
             logging::event_log(
-                Event::DataTransfer,
+                Event::Connection,
+                &format!("{} for {} from {}", req.method, req.target, src_addr),
+            );
+
+            // A request to upgrade may become a tunnel, so it needs room for one
+            let _slot = match req.upgrades().is_empty() {
+                true => TunnelSlot::default(),
+                false => match admission::tunnel_slot(&src_addr) {
+                    Ok(slot) => slot,
+                    Err(e) => return Err(reply_error(stream, e).await),
+                },
+            };
+            let (mut t_stream, host, port, path) = match connect_origin(&fwall, config, src_addr, local_ip, &req).await {
+                Ok(connected) => connected,
+                Err(e) => return Err(reply_block_page(stream, block_page, &req, e).await),
+            };
+            let dst_addr = t_stream
+                .peer_addr()
+                .map_err(|e| ProxyError::IO(format!("While getting the socket address {:?}", e)))?
+                .ip();
+
+            logging::event_log(
+                Event::ProxyServer,
+                &format!("{} and {} verified", src_addr, dst_addr),
+            );
+
+            let pseudonym = &config.listener.via_pseudonym;
+            let head = build_origin_request(&req, &path, &host, port, src_addr, anonymity, pseudonym);
+            let forwarded = match forward_request(stream, &mut t_stream, &req, &head, body_prefix, anonymity, pseudonym)
+                .await
+            {
+                Ok(forwarded) => forwarded,
+                Err(e @ ProxyError::InvalidResponse(_)) => return Err(reply_error(stream, e).await),
+                Err(e) => return Err(e),
+            };
+
+            logging::event_log(
+                Event::Connection,
                 &format!(
-                    "Total {} bytes exchanged between {} and {}",
-                    n, src_addr, dst_addr
+                    "{} for {} from {} answered with {}",
+                    req.method, req.target, src_addr, forwarded.status
                 ),
             );
 
-            Ok(())
-        }
+            // From here on the connections carry the new protocol, which is tunnelled like CONNECT
+            if let Some(protocol) = forwarded.upgraded {
+                logging::event_log(
+                    Event::Connection,
+                    &format!(
+                        "Connection between {} and {} upgraded to {}",
+                        src_addr, dst_addr, protocol
+                    ),
+                );
+
+                if !forwarded.pending.is_empty() {
+                    write_to_tcpstream(&mut t_stream, &forwarded.pending).await?;
+                }
+
+                let (n, result) = tunnel(stream, &mut t_stream, config).await;
+                let total = forwarded.total as usize + forwarded.pending.len() + n;
+                log_tunnel_total(total, src_addr, dst_addr, Some(&protocol));
+                return result.map(|()| None);
+            }
 
-        Ok(ReqType::GET(p)) => {
             logging::event_log(
-                Event::Connection,
-                &format!("GET for {} from {}", p, src_addr),
+                Event::DataTransfer,
+                &format!(
+                    "Total {} bytes exchanged between {} and {}",
+                    forwarded.total, src_addr, dst_addr
+                ),
             );
-            Ok(())
+
+            match forwarded.keep_alive {
+                true => Ok(Some(forwarded.pending)),
+                false => {
+                    let _res = stream.shutdown().await;
+                    Ok(None)
+                }
+            }
         }
 
+        // The client closed the connection instead of sending another request
+        Err(ProxyError::StreamClosed) => Err(ProxyError::StreamClosed),
+
         Err(e) => {
             logging::event_log(
                 Event::Connection,
                 &format!("[{:?}] while parsing request", e),
             );
-            Err(e)
+            Err(reply_error(stream, e).await)
         }
     }
 }
 
 #[cfg(test)]
-mod test_req_handler {}
+mod test_req_handler {
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_origin_target() {
+        let (host, port, path) = origin_target("http://example.com/a/b?c=d").unwrap();
+        assert_eq!((host.as_str(), port), ("example.com", 80));
+        assert_eq!(path, "/a/b?c=d");
+
+        let (host, port, path) = origin_target("http://[::1]:8080").unwrap();
+        assert_eq!((host.as_str(), port), ("::1", 8080));
+        assert_eq!(path, "/");
+
+        assert!(origin_target("/relative").is_err());
+        assert!(origin_target("ftp://example.com/").is_err());
+    }
+
+    #[test]
+    fn test_split_authority() {
+        assert_eq!(
+            split_authority("example.com:443").unwrap(),
+            ("example.com".to_owned(), 443)
+        );
+        assert_eq!(
+            split_authority("[2001:db8::1]:8443").unwrap(),
+            ("2001:db8::1".to_owned(), 8443)
+        );
+        assert!(split_authority("example.com").is_err());
+        assert!(split_authority(":443").is_err());
+    }
+
+    #[test]
+    fn test_host_header() {
+        let mut req = HttpRequest {
+            method: "GET".to_owned(),
+            target: "http://example.com/".to_owned(),
+            version: 1,
+            headers: vec![Header::new("Host", b"www.example.com:8080")],
+            framing: BodyFraming::NoBody,
+        };
+        assert_eq!(host_header(&req).as_deref(), Some("www.example.com"));
+
+        req.headers = vec![Header::new("Host", b"[::1]:8080")];
+        assert_eq!(host_header(&req).as_deref(), Some("::1"));
+
+        req.headers = vec![];
+        assert_eq!(host_header(&req), None);
+    }
+
+    #[test]
+    fn test_determine_request() {
+        let raw = b"POST http://example.com/form HTTP/1.1\r\nHost: example.com\r\nContent-Length: 3\r\n\r\na=b";
+
+        let (req_type, head_len) = determine_request(raw).unwrap().unwrap();
+        assert_eq!(&raw[head_len..], b"a=b");
+
+        match req_type {
+            ReqType::HTTP(req) => {
+                assert_eq!(req.method, "POST");
+                assert_eq!(req.target, "http://example.com/form");
+                assert_eq!(req.framing, BodyFraming::ContentLength(3));
+            }
+            _ => panic!("Expected HTTP request"),
+        }
+
+        let raw = b"CONNECT example.com:443 HTTP/1.1\r\n\r\n";
+        assert!(matches!(
+            determine_request(raw).unwrap().unwrap().0,
+            ReqType::CONNECT(_, _)
+        ));
+
+        assert!(determine_request(b"GET http://example.com/ HTTP/1.1\r\nHost: exa").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_build_origin_request() {
+        let req = HttpRequest {
+            method: "PUT".to_owned(),
+            target: "http://example.com/index.html".to_owned(),
+            version: 1,
+            headers: vec![
+                Header::new("Host", b"example.com"),
+                Header::new("Proxy-Connection", b"keep-alive"),
+                Header::new("Proxy-Authorization", b"Basic YWxpY2U6b3BlbiBzZXNhbWU="),
+                Header::new("Expect", b"100-continue"),
+                Header::new("Content-Length", b"0"),
+            ],
+            framing: BodyFraming::ContentLength(0),
+        };
+        let src_addr = "192.0.2.1".parse().unwrap();
+        let head = build_origin_request(&req, "/index.html", "example.com", 80, src_addr, Anonymity::Elite, "shallot");
+
+        assert_eq!(
+            head,
+            b"PUT /index.html HTTP/1.1\r\nHost: example.com\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+
+        // The origin must not be able to pick a different body length than the proxy relays
+        let mut req = req;
+        req.headers.push(Header::new("Transfer-Encoding", b"chunked"));
+        req.framing = BodyFraming::Chunked;
+        let head = build_origin_request(&req, "/index.html", "example.com", 80, src_addr, Anonymity::Elite, "shallot");
+        assert_eq!(
+            head,
+            b"PUT /index.html HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_forwarding_headers() {
+        let req = HttpRequest {
+            method: "GET".to_owned(),
+            target: "http://example.com/".to_owned(),
+            version: 0,
+            headers: vec![
+                Header::new("Connection", b"X-Secret, Content-Length"),
+                Header::new("X-Secret", b"1"),
+                Header::new("Upgrade", b"websocket"),
+                Header::new("TE", b"trailers"),
+                Header::new("Via", b"1.1 upstream"),
+                Header::new("X-Forwarded-For", b"10.0.0.1"),
+                Header::new("Content-Length", b"0"),
+            ],
+            framing: BodyFraming::ContentLength(0),
+        };
+        let src_addr = "::ffff:192.0.2.1".parse().unwrap();
+
+        let head = build_origin_request(&req, "/", "example.com", 80, src_addr, Anonymity::Transparent, "shallot");
+        assert_eq!(
+            String::from_utf8(head).unwrap(),
+            "GET / HTTP/1.1\r\nHost: example.com:80\r\nContent-Length: 0\r\nVia: 1.1 upstream, 1.0 shallot\r\n\
+             X-Forwarded-For: 10.0.0.1, 192.0.2.1\r\nForwarded: for=192.0.2.1;proto=http\r\nConnection: close\r\n\r\n"
+        );
+
+        let head = build_origin_request(&req, "/", "example.com", 80, src_addr, Anonymity::Anonymous, "shallot");
+        assert_eq!(
+            String::from_utf8(head).unwrap(),
+            "GET / HTTP/1.1\r\nHost: example.com:80\r\nContent-Length: 0\r\nVia: 1.1 upstream, 1.0 shallot\r\n\
+             Connection: close\r\n\r\n"
+        );
+
+        // Only an upgrade the client asks for in Connection is passed on
+        let mut req = req;
+        req.headers[0] = Header::new("Connection", b"Upgrade");
+        let head = build_origin_request(&req, "/", "example.com", 80, src_addr, Anonymity::Elite, "shallot");
+        assert!(head.ends_with(b"\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_build_client_response() {
+        let raw = b"HTTP/1.1 200 OK\r\nConnection: close\r\nKeep-Alive: timeout=5\r\nContent-Length: 2\r\n\r\n".to_vec();
+        let resp = http_message::parse_response(raw).unwrap();
+
+        assert_eq!(
+            build_client_response(&resp, true, Anonymity::Anonymous, "shallot"),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nVia: 1.1 shallot\r\nConnection: keep-alive\r\n\r\n"
+        );
+        assert_eq!(
+            build_client_response(&resp, false, Anonymity::Elite, "shallot"),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_loop_detection() {
+        let src_addr = "192.0.2.1".parse().unwrap();
+        let via = vec![Header::new("Via", b"1.1 upstream, 1.0 Shallot (Shallot/0.1)")];
+        assert!(matches!(check_via(&via, "shallot", &src_addr), Err(ProxyError::LoopDetected)));
+        assert!(check_via(&via, "shallot-2", &src_addr).is_ok());
+
+        let listeners: Vec<SocketAddr> = vec!["127.0.0.1:7878".parse().unwrap(), "[::]:3128".parse().unwrap()];
+        let local_ip = "192.0.2.10".parse().unwrap();
+        let own = |addr: &str| check_own_address(&[addr.parse().unwrap()], &listeners, local_ip, &src_addr).is_err();
+
+        assert!(own("127.0.0.1:7878"));
+        assert!(own("0.0.0.0:7878"));
+        assert!(own("[::ffff:127.0.0.1]:7878"));
+        assert!(own("192.0.2.10:3128"));
+        assert!(own("127.0.0.2:3128"));
+        assert!(!own("127.0.0.1:8080"));
+        assert!(!own("192.0.2.10:7878"));
+        assert!(!own("198.51.100.1:3128"));
+    }
+
+    #[test]
+    fn test_error_response() {
+        let response = ErrorResponse::new(&ProxyError::BlackListDeny).unwrap().to_http1();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(response.contains(
+            "Proxy-Status: shallot; error=http_request_denied; details=\"The destination is in the blacklist\"\r\n"
+        ));
+        assert!(response.ends_with("\r\n\r\n403 Forbidden\nThe destination is in the blacklist\n"));
+
+        let response = ErrorResponse::new(&ProxyError::ProxyAuthRequired).unwrap().to_http1();
+        let challenge = format!("\r\nProxy-Authenticate: {}\r\n", PROXY_AUTHENTICATE);
+        assert!(String::from_utf8(response).unwrap().contains(&challenge));
+
+        assert!(ErrorResponse::new(&ProxyError::StreamClosed).is_none());
+        assert_eq!(sf_string("a \"quoted\" \\ \u{e9}"), "\"a \\\"quoted\\\" \\\\ \"");
+    }
+
+    #[tokio::test]
+    async fn test_get_req_type() {
+        // Split across reads, with the start of the body read along with the head
+        let mut raw: &[u8] = b"POST http://example.com/ HTTP/1.1\r\nCookie: a=b\r\nContent-Length: 4\r\n\r\nbody";
+        let (req_type, rest) = get_req_type(&mut raw, vec![], 16, 1024).await.unwrap();
+        assert!(matches!(req_type, ReqType::HTTP(_)));
+        assert_eq!(rest, b"body");
+
+        // A pipelined request already read along with the previous one is parsed without reading
+        let pipelined = b"GET http://example.com/a HTTP/1.1\r\n\r\nGET http://example.com/b HTTP/1.1\r\n\r\n";
+        let (req_type, rest) = get_req_type(&mut &b""[..], pipelined.to_vec(), 512, 1024).await.unwrap();
+        assert!(matches!(req_type, ReqType::HTTP(req) if req.target == "http://example.com/a"));
+        assert_eq!(rest, b"GET http://example.com/b HTTP/1.1\r\n\r\n");
+
+        let raw = format!("GET http://example.com/ HTTP/1.1\r\nCookie: {}\r\n\r\n", "a".repeat(2000));
+        assert!(matches!(
+            get_req_type(&mut raw.as_bytes(), vec![], 512, 1024).await,
+            Err(ProxyError::HeaderTooLarge)
+        ));
+
+        assert!(matches!(
+            get_req_type(&mut &b"GET http://example.com/ HTTP/1.1\r\n"[..], vec![], 512, 1024).await,
+            Err(ProxyError::StreamClosed)
+        ));
+    }
+
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    /// A tunnel buffer that counts how many times the tunnel tries to send through it
+    struct CountingBuffer(TunnelBuffer, Arc<AtomicUsize>);
+
+    impl TunnelDirection for CountingBuffer {
+        fn pending(&self) -> usize {
+            self.0.pending()
+        }
+
+        fn send(&mut self, src: &TcpStream, dst: &TcpStream) -> Result<usize> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.send(src, dst)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_both_ways() {
+        let (mut client, mut s_stream) = socket_pair().await;
+        let (mut t_stream, mut server) = socket_pair().await;
+        let attempts = Arc::new(AtomicUsize::new(0));
+
+        let bufs = (
+            CountingBuffer(TunnelBuffer(0, vec![0; 1024]), attempts.clone()),
+            CountingBuffer(TunnelBuffer(0, vec![0; 1024]), attempts.clone()),
+        );
+        let tunnel = tokio::spawn(async move {
+            tunnel_with(&mut s_stream, &mut t_stream, bufs.0, bufs.1, &TimeoutsConfig::default()).await
+        });
+
+        // Both ways carry many times what fits in a buffer
+        let request = vec![b'q'; 100_000];
+        let mut received = vec![0; request.len()];
+        let (sent, read) = tokio::join!(client.write_all(&request), server.read_exact(&mut received));
+        sent.unwrap();
+        read.unwrap();
+        assert_eq!(received, request);
+
+        let reply = vec![b'r'; 50_000];
+        let mut received = vec![0; reply.len()];
+        let (sent, read) = tokio::join!(server.write_all(&reply), client.read_exact(&mut received));
+        sent.unwrap();
+        read.unwrap();
+        assert_eq!(received, reply);
+
+        // With nothing to move the tunnel waits for the sockets instead of trying again and again
+        let before = attempts.load(Ordering::Relaxed);
+        time::sleep(Duration::from_millis(300)).await;
+        assert!(attempts.load(Ordering::Relaxed) - before <= 4);
+
+        client.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        server.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        drop(server);
+
+        let (n, result) = tunnel.await.unwrap();
+        assert!(result.is_ok());
+        assert_eq!(n, 150_000);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_half_close() {
+        let (mut client, mut s_stream) = socket_pair().await;
+        let (mut t_stream, mut server) = socket_pair().await;
+
+        let tunnel = tokio::spawn(async move {
+            let bufs = (TunnelBuffer(0, vec![0; 1024]), TunnelBuffer(0, vec![0; 1024]));
+            tunnel_with(&mut s_stream, &mut t_stream, bufs.0, bufs.1, &TimeoutsConfig::default()).await
+        });
+
+        // The client is done sending, but still waits for the reply
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut request = Vec::new();
+        server.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+
+        server.write_all(b"reply").await.unwrap();
+        server.shutdown().await.unwrap();
+
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"reply");
+
+        let (n, result) = tunnel.await.unwrap();
+        assert!(result.is_ok());
+        assert_eq!(n, 12);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_reset() {
+        let (mut client, mut s_stream) = socket_pair().await;
+        let (mut t_stream, mut server) = socket_pair().await;
+
+        let tunnel = tokio::spawn(async move {
+            let bufs = (TunnelBuffer(0, vec![0; 1024]), TunnelBuffer(0, vec![0; 1024]));
+            tunnel_with(&mut s_stream, &mut t_stream, bufs.0, bufs.1, &TimeoutsConfig::default()).await
+        });
+
+        client.write_all(b"request").await.unwrap();
+        let mut request = [0; 7];
+        server.read_exact(&mut request).await.unwrap();
+
+        // Closing with a zero linger time resets the connection, which isn't a clean end of the tunnel, but what was
+        // moved before it is still counted
+        client.set_zero_linger().unwrap();
+        drop(client);
+        let (n, result) = tunnel.await.unwrap();
+        assert!(matches!(result, Err(ProxyError::IO(_))));
+        assert_eq!(n, 7);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_idle_timeout() {
+        let (mut client, mut s_stream) = socket_pair().await;
+        let (mut t_stream, _server) = socket_pair().await;
+        let timeouts = TimeoutsConfig {
+            tunnel_idle_secs: 1,
+            ..TimeoutsConfig::default()
+        };
+
+        let bufs = (TunnelBuffer(0, vec![0; 1024]), TunnelBuffer(0, vec![0; 1024]));
+        let (_, result) = tunnel_with(&mut s_stream, &mut t_stream, bufs.0, bufs.1, &timeouts).await;
+        assert!(matches!(result, Err(ProxyError::TunnelIdleTimeout)));
+
+        // The client is disconnected
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+}
@@ -0,0 +1,417 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use publicsuffix::List;
+
+/// A single address entry from the whitelist or blacklist. Supported forms:
+///
+/// * `10.1.2.3`, `2001:db8::1` - a single address
+/// * `10.0.0.0/8`, `2001:db8::/32` - a CIDR block
+/// * `192.168.1.10-192.168.1.50` - an inclusive range of addresses
+/// * `172.16.*.*` - the original IPv4 octet wildcard form
+#[derive(Debug, Clone, PartialEq)]
+pub enum AddrRule {
+    Cidr(IpAddr, u8),
+    Range(IpAddr, IpAddr),
+    Wildcard([Option<u8>; 4]),
+}
+
+impl AddrRule {
+    /// Returns true if the address falls under this rule. IPv4-mapped IPv6
+    /// addresses are matched as the IPv4 address they carry.
+    pub fn matches(&self, addr: &IpAddr) -> bool {
+        let addr = canonical(addr);
+
+        match self {
+            AddrRule::Cidr(net, prefix) => match (net, addr) {
+                (IpAddr::V4(net), IpAddr::V4(a)) => {
+                    prefix_match(u32::from(*net) as u128, u32::from(a) as u128, *prefix, 32)
+                }
+                (IpAddr::V6(net), IpAddr::V6(a)) => {
+                    prefix_match(u128::from(*net), u128::from(a), *prefix, 128)
+                }
+                _ => false,
+            },
+
+            AddrRule::Range(start, end) => match (start, end, addr) {
+                (IpAddr::V4(s), IpAddr::V4(e), IpAddr::V4(a)) => *s <= a && a <= *e,
+                (IpAddr::V6(s), IpAddr::V6(e), IpAddr::V6(a)) => *s <= a && a <= *e,
+                _ => false,
+            },
+
+            AddrRule::Wildcard(octets) => match addr {
+                IpAddr::V4(a) => octets
+                    .iter()
+                    .zip(a.octets().iter())
+                    .all(|(rule, octet)| rule.is_none_or(|r| r == *octet)),
+                IpAddr::V6(_) => false,
+            },
+        }
+    }
+}
+
+impl FromStr for AddrRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AddrRule, String> {
+        let s = s.trim();
+
+        if let Some((start, end)) = s.split_once('-') {
+            let start = parse_addr(start)?;
+            let end = parse_addr(end)?;
+            if start.is_ipv4() != end.is_ipv4() {
+                return Err(format!("Range {} mixes IPv4 and IPv6", s));
+            }
+            if start > end {
+                return Err(format!("Range {} ends before it starts", s));
+            }
+            return Ok(AddrRule::Range(start, end));
+        }
+
+        if let Some((net, prefix)) = s.split_once('/') {
+            let net = parse_addr(net)?;
+            let max = if net.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix.trim().parse::<u8>() {
+                Ok(p) if p <= max => p,
+                _ => return Err(format!("Invalid prefix length in {}", s)),
+            };
+            return Ok(AddrRule::Cidr(net, prefix));
+        }
+
+        if s.contains('*') {
+            let parts: Vec<&str> = s.split('.').collect();
+            if parts.len() != 4 {
+                return Err(format!("Wildcard {} must have four octets", s));
+            }
+
+            let mut octets = [None; 4];
+            for (i, part) in parts.iter().enumerate() {
+                if *part != "*" {
+                    let octet = part
+                        .parse::<u8>()
+                        .map_err(|_| format!("Invalid octet {} in {}", part, s))?;
+                    octets[i] = Some(octet);
+                }
+            }
+            return Ok(AddrRule::Wildcard(octets));
+        }
+
+        let addr = parse_addr(s)?;
+        let prefix = if addr.is_ipv4() { 32 } else { 128 };
+        Ok(AddrRule::Cidr(addr, prefix))
+    }
+}
+
+impl fmt::Display for AddrRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddrRule::Cidr(addr, 32) if addr.is_ipv4() => write!(f, "{}", addr),
+            AddrRule::Cidr(addr, 128) => write!(f, "{}", addr),
+            AddrRule::Cidr(net, prefix) => write!(f, "{}/{}", net, prefix),
+            AddrRule::Range(start, end) => write!(f, "{}-{}", start, end),
+            AddrRule::Wildcard(octets) => {
+                let parts: Vec<String> = octets
+                    .iter()
+                    .map(|o| o.map_or("*".to_owned(), |o| o.to_string()))
+                    .collect();
+                write!(f, "{}", parts.join("."))
+            }
+        }
+    }
+}
+
+fn parse_addr(s: &str) -> Result<IpAddr, String> {
+    let s = s.trim().trim_start_matches('[').trim_end_matches(']');
+    match s.parse::<IpAddr>() {
+        Ok(addr) => Ok(canonical(&addr)),
+        Err(_) => Err(format!("Invalid address {}", s)),
+    }
+}
+
+/// Map IPv4-mapped IPv6 addresses (::ffff:a.b.c.d) to plain IPv4
+fn canonical(addr: &IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(*v6),
+        },
+        IpAddr::V4(_) => *addr,
+    }
+}
+
+/// Compare the first `prefix` bits of two addresses that are `bits` wide
+fn prefix_match(net: u128, addr: u128, prefix: u8, bits: u32) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let shift = bits - prefix as u32;
+    (net >> shift) == (addr >> shift)
+}
+
+/// A hostname entry from a list file, matched against the CONNECT authority
+/// or the host of a forwarded request. Supported forms:
+///
+/// * `ads.example.net` - exactly this host
+/// * `*.facebook.com` - any subdomain of facebook.com, but not facebook.com
+/// * `.facebook.com` - facebook.com and any of its subdomains
+/// * `site:example.co.uk` - any host whose registrable domain (eTLD+1,
+///   according to the public suffix list) is example.co.uk
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostRule {
+    Exact(String),
+    Subdomains(String),
+    Domain(String),
+    Registrable(String),
+}
+
+impl HostRule {
+    /// Parse a hostname rule. The public suffix list, if available, is used
+    /// to reject `site:` rules naming a public suffix such as `co.uk`, which
+    /// would otherwise never match anything.
+    pub fn parse(s: &str, psl: Option<&List>) -> Result<HostRule, String> {
+        let s = s.trim();
+
+        if let Some(domain) = s.strip_prefix("site:") {
+            let domain = normalize_host(domain);
+            check_host_syntax(&domain)?;
+
+            if let Some(psl) = psl {
+                let root = psl
+                    .parse_domain(&domain)
+                    .map_err(|_| format!("Invalid domain {}", domain))?
+                    .root()
+                    .map(|r| r.to_owned());
+                if root.as_deref() != Some(domain.as_str()) {
+                    return Err(format!("{} is not a registrable domain", domain));
+                }
+            }
+            return Ok(HostRule::Registrable(domain));
+        }
+
+        if let Some(domain) = s.strip_prefix("*.") {
+            let domain = normalize_host(domain);
+            check_host_syntax(&domain)?;
+            return Ok(HostRule::Subdomains(domain));
+        }
+
+        if let Some(domain) = s.strip_prefix('.') {
+            let domain = normalize_host(domain);
+            check_host_syntax(&domain)?;
+            return Ok(HostRule::Domain(domain));
+        }
+
+        let host = normalize_host(s);
+        check_host_syntax(&host)?;
+        Ok(HostRule::Exact(host))
+    }
+
+    /// Returns true if the host falls under this rule. Without a public
+    /// suffix list, `site:` rules fall back to matching the domain and all
+    /// of its subdomains.
+    pub fn matches(&self, host: &str, psl: Option<&List>) -> bool {
+        let host = normalize_host(host);
+
+        match self {
+            HostRule::Exact(h) => host == *h,
+            HostRule::Subdomains(d) => is_subdomain(&host, d),
+            HostRule::Domain(d) => host == *d || is_subdomain(&host, d),
+            HostRule::Registrable(d) => match psl {
+                Some(psl) => match psl.parse_domain(&host) {
+                    Ok(domain) => domain.root() == Some(d.as_str()),
+                    Err(_) => false,
+                },
+                None => host == *d || is_subdomain(&host, d),
+            },
+        }
+    }
+}
+
+impl fmt::Display for HostRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostRule::Exact(h) => write!(f, "{}", h),
+            HostRule::Subdomains(d) => write!(f, "*.{}", d),
+            HostRule::Domain(d) => write!(f, ".{}", d),
+            HostRule::Registrable(d) => write!(f, "site:{}", d),
+        }
+    }
+}
+
+/// Hostnames are compared in lower case and without a trailing dot
+fn normalize_host(host: &str) -> String {
+    host.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn is_subdomain(host: &str, domain: &str) -> bool {
+    host.len() > domain.len()
+        && host.ends_with(domain)
+        && host.as_bytes()[host.len() - domain.len() - 1] == b'.'
+}
+
+/// Hostname rules may only contain letters, digits, '-' and '.', and must
+/// not be all digits and dots, which would be a mistyped address instead.
+fn check_host_syntax(host: &str) -> Result<(), String> {
+    let valid = !host.is_empty()
+        && host
+            .split('.')
+            .all(|label| !label.is_empty() && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-'))
+        && !host.bytes().all(|b| b.is_ascii_digit() || b == b'.');
+
+    match valid {
+        true => Ok(()),
+        false => Err(format!("Invalid hostname {}", host)),
+    }
+}
+
+/// The parsed contents of a list file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuleList {
+    pub addrs: Vec<AddrRule>,
+    pub hosts: Vec<HostRule>,
+}
+
+impl RuleList {
+    /// Parse a line of a list file into an address or hostname rule
+    pub fn add_line(&mut self, line: &str, psl: Option<&List>) -> Result<(), String> {
+        if let Ok(rule) = line.parse::<AddrRule>() {
+            self.addrs.push(rule);
+            return Ok(());
+        }
+
+        match HostRule::parse(line, psl) {
+            Ok(rule) => {
+                self.hosts.push(rule);
+                Ok(())
+            }
+            // site: rules have their own, more useful, errors
+            Err(e) if line.starts_with("site:") => Err(e),
+            Err(_) => Err(format!("{} is neither an address nor a hostname", line)),
+        }
+    }
+
+    /// Returns the first rule matching the address or hostname, as it would
+    /// be written in the list file
+    pub fn find(&self, target: &str, psl: Option<&List>) -> Option<String> {
+        match target.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            Ok(ip) => self.addrs.iter().find(|rule| rule.matches(&ip)).map(|r| r.to_string()),
+            Err(_) => self
+                .hosts
+                .iter()
+                .find(|rule| rule.matches(target, psl))
+                .map(|r| r.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_rules {
+
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let rule: AddrRule = "10.0.0.0/8".parse().unwrap();
+        assert!(rule.matches(&ip("10.200.3.4")));
+        assert!(!rule.matches(&ip("11.0.0.1")));
+        assert!(rule.matches(&ip("::ffff:10.0.0.1")));
+
+        let rule: AddrRule = "2001:db8::/32".parse().unwrap();
+        assert!(rule.matches(&ip("2001:db8:ffff::1")));
+        assert!(!rule.matches(&ip("2001:db9::1")));
+        assert!(!rule.matches(&ip("10.0.0.1")));
+
+        let rule: AddrRule = "0.0.0.0/0".parse().unwrap();
+        assert!(rule.matches(&ip("203.0.113.9")));
+
+        assert!("10.0.0.0/33".parse::<AddrRule>().is_err());
+    }
+
+    #[test]
+    fn test_range() {
+        let rule: AddrRule = "192.168.1.10-192.168.1.50".parse().unwrap();
+        assert!(rule.matches(&ip("192.168.1.10")));
+        assert!(rule.matches(&ip("192.168.1.50")));
+        assert!(!rule.matches(&ip("192.168.1.51")));
+
+        assert!("192.168.1.50-192.168.1.10".parse::<AddrRule>().is_err());
+        assert!("10.0.0.1-::1".parse::<AddrRule>().is_err());
+    }
+
+    #[test]
+    fn test_wildcard_and_single() {
+        let rule: AddrRule = "172.0.0.*".parse().unwrap();
+        assert!(rule.matches(&ip("172.0.0.9")));
+        assert!(!rule.matches(&ip("172.0.1.9")));
+
+        let rule: AddrRule = "*.*.*.*".parse().unwrap();
+        assert!(rule.matches(&ip("1.2.3.4")));
+        assert!(!rule.matches(&ip("::1")));
+
+        let rule: AddrRule = "::1".parse().unwrap();
+        assert!(rule.matches(&ip("::1")));
+        assert!(!rule.matches(&ip("::2")));
+
+        assert!("1.2.3".parse::<AddrRule>().is_err());
+        assert!("example.com".parse::<AddrRule>().is_err());
+    }
+
+    #[test]
+    fn test_host_rules() {
+        let rule = HostRule::parse("ads.example.net", None).unwrap();
+        assert!(rule.matches("ADS.example.net.", None));
+        assert!(!rule.matches("x.ads.example.net", None));
+
+        let rule = HostRule::parse("*.facebook.com", None).unwrap();
+        assert!(rule.matches("www.facebook.com", None));
+        assert!(!rule.matches("facebook.com", None));
+        assert!(!rule.matches("notfacebook.com", None));
+
+        let rule = HostRule::parse(".facebook.com", None).unwrap();
+        assert!(rule.matches("facebook.com", None));
+        assert!(rule.matches("a.b.facebook.com", None));
+
+        assert!(HostRule::parse("bad_host.com", None).is_err());
+    }
+
+    #[test]
+    fn test_registrable_rules() {
+        let psl = List::from_str("// ===BEGIN ICANN DOMAINS===\nuk\nco.uk\ncom\n").unwrap();
+
+        let rule = HostRule::parse("site:example.co.uk", Some(&psl)).unwrap();
+        assert!(rule.matches("example.co.uk", Some(&psl)));
+        assert!(rule.matches("www.shop.example.co.uk", Some(&psl)));
+        assert!(!rule.matches("other.co.uk", Some(&psl)));
+
+        assert!(HostRule::parse("site:co.uk", Some(&psl)).is_err());
+        assert!(HostRule::parse("site:www.example.co.uk", Some(&psl)).is_err());
+    }
+
+    #[test]
+    fn test_rule_list() {
+        let mut list = RuleList::default();
+        list.add_line("10.0.0.0/8", None).unwrap();
+        list.add_line("*.example.com", None).unwrap();
+        assert!(list.add_line("10.0.0.300", None).is_err());
+        assert!(list.add_line("2001:db8::zz", None).is_err());
+
+        assert_eq!(list.find("10.2.3.4", None).as_deref(), Some("10.0.0.0/8"));
+        assert_eq!(list.find("www.example.com", None).as_deref(), Some("*.example.com"));
+        assert_eq!(list.find("example.com", None), None);
+        assert_eq!(list.find("::1", None), None);
+    }
+
+    #[test]
+    fn test_display() {
+        for rule in ["10.1.2.3", "10.0.0.0/8", "2001:db8::/32", "::1", "10.0.0.1-10.0.0.9", "172.16.*.*"] {
+            assert_eq!(rule.parse::<AddrRule>().unwrap().to_string(), rule);
+        }
+        for rule in ["ads.example.net", "*.facebook.com", ".facebook.com", "site:example.co.uk"] {
+            assert_eq!(HostRule::parse(rule, None).unwrap().to_string(), rule);
+        }
+    }
+}
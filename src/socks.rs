@@ -0,0 +1,534 @@
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+
+use crate::admission;
+use crate::auth::AuthResult;
+use crate::config::Config;
+use crate::firewall::Firewall;
+use crate::firewall::SharedFirewall;
+use crate::logging;
+use crate::logging::Event;
+use crate::proxy_listener::ProxyError;
+use crate::proxy_listener::Result;
+use crate::request_handler;
+
+const SOCKS4: u8 = 4;
+const SOCKS5: u8 = 5;
+
+/// SOCKS5 authentication methods (RFC 1928 section 3)
+const NO_AUTH: u8 = 0x00;
+const USER_PASS: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+/// Version of the username/password subnegotiation (RFC 1929)
+const USER_PASS_VERSION: u8 = 1;
+
+/// The only command supported, in both SOCKS4 and SOCKS5
+const CONNECT: u8 = 1;
+
+/// SOCKS5 address types
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+
+/// SOCKS5 replies (RFC 1928 section 6)
+const SUCCEEDED: u8 = 0;
+const GENERAL_FAILURE: u8 = 1;
+const NOT_ALLOWED: u8 = 2;
+const HOST_UNREACHABLE: u8 = 4;
+const CONNECTION_REFUSED: u8 = 5;
+const COMMAND_NOT_SUPPORTED: u8 = 7;
+const ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+/// SOCKS4 replies
+const SOCKS4_GRANTED: u8 = 90;
+const SOCKS4_REJECTED: u8 = 91;
+
+/// Longest user ID or hostname accepted in a SOCKS4 request, the same as SOCKS5 allows
+const SOCKS4_MAX_STRING: usize = 255;
+
+/// A CONNECT request negotiated with a SOCKS client
+#[derive(Debug, PartialEq)]
+struct SocksRequest {
+    version: u8,
+    host: String,
+    port: u16,
+    /// The authenticated user, if authentication is on
+    user: Option<String>,
+}
+
+/// Handle a connection to a SOCKS listener. The client is vetted like an HTTP client, by the whitelist unless there's
+/// a policy file and with the same user store, and its CONNECT request goes through the same checks and logging as an
+/// HTTP CONNECT before it's tunnelled.
+pub async fn process_connection(stream: &mut TcpStream, firewall: &SharedFirewall, config: &Config) -> Result<()> {
+    let src_addr = stream
+        .peer_addr()
+        .map_err(|e| ProxyError::IO(format!("While getting the socket address {:?}", e)))?
+        .ip();
+    let local_ip = stream
+        .local_addr()
+        .map_err(|e| ProxyError::IO(format!("While getting the socket address {:?}", e)))?
+        .ip();
+
+    let fwall = firewall.snapshot();
+    let timeout = config.timeouts.request_header();
+    let request = match time::timeout(timeout, negotiate(stream, &fwall, config, src_addr)).await {
+        Ok(request) => request?,
+        Err(_) => {
+            logging::event_log(
+                Event::Timeout,
+                &format!("No SOCKS request from {} within {}s", src_addr, timeout.as_secs()),
+            );
+            return Err(ProxyError::RequestTimeout);
+        }
+    };
+
+    let protocol = match request.version {
+        SOCKS4 => "SOCKS4",
+        _ => "SOCKS5",
+    };
+    let authority = match request.host.contains(':') {
+        true => format!("[{}]:{}", request.host, request.port),
+        false => format!("{}:{}", request.host, request.port),
+    };
+    logging::event_log(
+        Event::Connection,
+        &format!("{} CONNECT request for {} from {}", protocol, authority, src_addr),
+    );
+
+    let _slot = match admission::tunnel_slot(&src_addr) {
+        Ok(slot) => slot,
+        Err(e) => {
+            let _ = reply(stream, request.version, reply_code(&e), None).await;
+            return Err(e);
+        }
+    };
+    let mut t_stream = match request_handler::connect_tunnel_destination(
+        &fwall,
+        config,
+        request.user.as_deref(),
+        src_addr,
+        local_ip,
+        &request.host,
+        request.port,
+    )
+    .await
+    {
+        Ok(t_stream) => t_stream,
+        Err(e) => {
+            let _ = reply(stream, request.version, reply_code(&e), None).await;
+            return Err(e);
+        }
+    };
+    let dst_addr = request_handler::peer_ip(&t_stream);
+
+    logging::event_log(
+        Event::ProxyServer,
+        &format!("{} and {} verified", src_addr, dst_addr),
+    );
+
+    reply(stream, request.version, SUCCEEDED, t_stream.local_addr().ok()).await?;
+
+    logging::event_log(
+        Event::Connection,
+        &format!(
+            "{} tunnel established between {} and {}",
+            protocol, src_addr, dst_addr
+        ),
+    );
+
+    let (n, result) = request_handler::tunnel(stream, &mut t_stream, config).await;
+    request_handler::log_tunnel_total(n, src_addr, dst_addr, None);
+    result
+}
+
+/// Read a SOCKS client's greeting and request, authenticating it on the way. Refusals are answered here, in the
+/// client's version of the protocol.
+async fn negotiate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    fwall: &Firewall,
+    config: &Config,
+    src_addr: IpAddr,
+) -> Result<SocksRequest> {
+    let version = read_u8(stream).await?;
+
+    let refusal = match version {
+        SOCKS5 => [SOCKS5, NO_ACCEPTABLE_METHOD].to_vec(),
+        SOCKS4 if config.socks.socks4 => socks4_reply(SOCKS4_REJECTED, None).to_vec(),
+        SOCKS4 => {
+            let _ = write(stream, &socks4_reply(SOCKS4_REJECTED, None)).await;
+            return Err(ProxyError::Parse("SOCKS4 is not enabled".to_owned()));
+        }
+        version => return Err(ProxyError::Parse(format!("Unsupported SOCKS version {}", version))),
+    };
+
+    // Without a policy file clients are vetted by the whitelist before anything else, as over HTTP
+    if !fwall.policy_enabled() {
+        if let Err(e) = request_handler::check_source(fwall, &src_addr) {
+            let _ = write(stream, &refusal).await;
+            return Err(e);
+        }
+    }
+
+    match version {
+        SOCKS5 => negotiate_socks5(stream, fwall, src_addr).await,
+        _ => negotiate_socks4(stream, fwall, src_addr).await,
+    }
+}
+
+async fn negotiate_socks5<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    fwall: &Firewall,
+    src_addr: IpAddr,
+) -> Result<SocksRequest> {
+    let n = read_u8(stream).await?;
+    let methods = read_bytes(stream, n as usize).await?;
+
+    let method = match fwall.auth_enabled() {
+        true => USER_PASS,
+        false => NO_AUTH,
+    };
+    if !methods.contains(&method) {
+        let _ = write(stream, &[SOCKS5, NO_ACCEPTABLE_METHOD]).await;
+        return match method {
+            USER_PASS => Err(auth_required(&src_addr)),
+            _ => Err(ProxyError::Parse("No acceptable SOCKS5 authentication method".to_owned())),
+        };
+    }
+    write(stream, &[SOCKS5, method]).await?;
+
+    let user = match method {
+        USER_PASS => {
+            if read_u8(stream).await? != USER_PASS_VERSION {
+                return Err(ProxyError::Parse("Invalid SOCKS5 username/password version".to_owned()));
+            }
+            let n = read_u8(stream).await?;
+            let user = String::from_utf8_lossy(&read_bytes(stream, n as usize).await?).into_owned();
+            let n = read_u8(stream).await?;
+            let password = String::from_utf8_lossy(&read_bytes(stream, n as usize).await?).into_owned();
+
+            let result = fwall.authenticate_user(&user, &password).await;
+            let status = match result {
+                AuthResult::User(_) => 0,
+                _ => 1,
+            };
+            write(stream, &[USER_PASS_VERSION, status]).await?;
+            request_handler::check_auth_result(result, &src_addr, true)?
+        }
+        _ => None,
+    };
+
+    let head = read_bytes(stream, 4).await?;
+    if head[0] != SOCKS5 {
+        return Err(ProxyError::Parse(format!("Unsupported SOCKS version {}", head[0])));
+    }
+    let host = match head[3] {
+        IPV4 => {
+            let octets: [u8; 4] = read_bytes(stream, 4).await?.try_into().unwrap_or_default();
+            Ipv4Addr::from(octets).to_string()
+        }
+        IPV6 => {
+            let octets: [u8; 16] = read_bytes(stream, 16).await?.try_into().unwrap_or_default();
+            Ipv6Addr::from(octets).to_string()
+        }
+        DOMAIN => {
+            let n = read_u8(stream).await?;
+            String::from_utf8(read_bytes(stream, n as usize).await?)
+                .map_err(|_| ProxyError::Parse("Invalid SOCKS5 domain name".to_owned()))?
+        }
+        address_type => {
+            let _ = reply(stream, SOCKS5, ADDRESS_NOT_SUPPORTED, None).await;
+            return Err(ProxyError::Parse(format!("Unsupported SOCKS5 address type {}", address_type)));
+        }
+    };
+    let port = read_u16(stream).await?;
+
+    if head[1] != CONNECT {
+        let _ = reply(stream, SOCKS5, COMMAND_NOT_SUPPORTED, None).await;
+        return Err(ProxyError::MethodNotAllowed(format!("SOCKS5 command {}", head[1])));
+    }
+
+    Ok(SocksRequest {
+        version: SOCKS5,
+        host,
+        port,
+        user,
+    })
+}
+
+/// SOCKS4 and SOCKS4a, which leaves the address as 0.0.0.x and sends a hostname after the user ID. The user ID is no
+/// proof of anything, so it's ignored, and clients are refused while authentication is on.
+async fn negotiate_socks4<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    fwall: &Firewall,
+    src_addr: IpAddr,
+) -> Result<SocksRequest> {
+    let command = read_u8(stream).await?;
+    let port = read_u16(stream).await?;
+    let ip: [u8; 4] = read_bytes(stream, 4).await?.try_into().unwrap_or_default();
+    let _user_id = read_cstring(stream).await?;
+
+    let host = match ip {
+        [0, 0, 0, n] if n != 0 => String::from_utf8(read_cstring(stream).await?)
+            .map_err(|_| ProxyError::Parse("Invalid SOCKS4a hostname".to_owned()))?,
+        ip => Ipv4Addr::from(ip).to_string(),
+    };
+
+    if fwall.auth_enabled() {
+        let _ = write(stream, &socks4_reply(SOCKS4_REJECTED, None)).await;
+        return Err(auth_required(&src_addr));
+    }
+    if command != CONNECT {
+        let _ = write(stream, &socks4_reply(SOCKS4_REJECTED, None)).await;
+        return Err(ProxyError::MethodNotAllowed(format!("SOCKS4 command {}", command)));
+    }
+
+    Ok(SocksRequest {
+        version: SOCKS4,
+        host,
+        port,
+        user: None,
+    })
+}
+
+/// Refuse a client that can't or won't authenticate, logged like an HTTP request without credentials
+fn auth_required(src_addr: &IpAddr) -> ProxyError {
+    match request_handler::check_auth_result(AuthResult::Denied, src_addr, false) {
+        Err(e) => e,
+        Ok(_) => ProxyError::ProxyAuthRequired,
+    }
+}
+
+/// The SOCKS5 reply for an error that ends a request
+fn reply_code(e: &ProxyError) -> u8 {
+    match e {
+        ProxyError::WhiteListDeny
+        | ProxyError::BlackListDeny
+        | ProxyError::PolicyDeny
+        | ProxyError::ProxyAuthRequired
+        | ProxyError::LoopDetected => NOT_ALLOWED,
+        ProxyError::CannotResolveDest | ProxyError::ConnectTimeout => HOST_UNREACHABLE,
+        ProxyError::CannotConnectToDest => CONNECTION_REFUSED,
+        _ => GENERAL_FAILURE,
+    }
+}
+
+/// Answer a request in the client's version of the protocol. `bound` is the address the proxy connected to the
+/// destination from, if it did.
+async fn reply<S: AsyncWrite + Unpin>(stream: &mut S, version: u8, code: u8, bound: Option<SocketAddr>) -> Result<()> {
+    if version == SOCKS4 {
+        let code = match code {
+            SUCCEEDED => SOCKS4_GRANTED,
+            _ => SOCKS4_REJECTED,
+        };
+        return write(stream, &socks4_reply(code, bound)).await;
+    }
+
+    let bound = bound.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+    let mut response = vec![SOCKS5, code, 0];
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            response.push(IPV4);
+            response.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            response.push(IPV6);
+            response.extend_from_slice(&ip.octets());
+        }
+    }
+    response.extend_from_slice(&bound.port().to_be_bytes());
+
+    write(stream, &response).await
+}
+
+fn socks4_reply(code: u8, bound: Option<SocketAddr>) -> [u8; 8] {
+    let mut response = [0u8; 8];
+    response[1] = code;
+    if let Some(SocketAddr::V4(bound)) = bound {
+        response[2..4].copy_from_slice(&bound.port().to_be_bytes());
+        response[4..].copy_from_slice(&bound.ip().octets());
+    }
+    response
+}
+
+async fn read_u8<R: AsyncRead + Unpin>(stream: &mut R) -> Result<u8> {
+    Ok(read_bytes(stream, 1).await?[0])
+}
+
+async fn read_u16<R: AsyncRead + Unpin>(stream: &mut R) -> Result<u16> {
+    let bytes = read_bytes(stream, 2).await?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+async fn read_bytes<R: AsyncRead + Unpin>(stream: &mut R, n: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; n];
+    match stream.read_exact(&mut buf).await {
+        Ok(_) => Ok(buf),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Err(ProxyError::StreamClosed),
+        Err(e) => Err(ProxyError::IO(format!("While reading {:?}", e))),
+    }
+}
+
+/// Read a NUL terminated string of SOCKS4, without the NUL
+async fn read_cstring<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Vec<u8>> {
+    let mut string = vec![];
+    loop {
+        match read_u8(stream).await? {
+            0 => return Ok(string),
+            _ if string.len() == SOCKS4_MAX_STRING => {
+                return Err(ProxyError::Parse("SOCKS4 request field too long".to_owned()))
+            }
+            c => string.push(c),
+        }
+    }
+}
+
+async fn write<W: AsyncWrite + Unpin>(stream: &mut W, buf: &[u8]) -> Result<()> {
+    stream
+        .write_all(buf)
+        .await
+        .map_err(|e| ProxyError::IO(format!("While writing {:?}", e)))
+}
+
+#[cfg(test)]
+mod test_socks {
+
+    use std::fs;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::config::ListsConfig;
+    use crate::config::LoggingConfig;
+
+    /// A firewall whitelisting localhost, with a user file holding alice if `auth` is set
+    fn firewall(name: &str, auth: bool) -> Firewall {
+        with_lists(name, auth, Firewall::new)
+    }
+
+    /// Build something from list files whitelisting localhost, which are removed again afterwards
+    fn with_lists<T>(name: &str, auth: bool, build: impl FnOnce(&ListsConfig) -> T) -> T {
+        let dir = std::env::temp_dir();
+        let path = |file: &str| dir.join(format!("shallot_test_socks_{}_{}", name, file)).to_str().unwrap().to_owned();
+        let config = ListsConfig {
+            whitelist: path("whitelist.txt"),
+            blacklist: path("blacklist.txt"),
+            policy: path("missing_policy.txt"),
+            users: path("users.htpasswd"),
+            public_suffix_list: path("missing_psl.dat"),
+        };
+        fs::write(&config.whitelist, "127.0.0.1\n").unwrap();
+        fs::write(&config.blacklist, "").unwrap();
+        if auth {
+            fs::write(&config.users, format!("alice:{}\n", bcrypt::hash("secret", 4).unwrap())).unwrap();
+        }
+
+        let built = build(&config);
+        for file in [&config.whitelist, &config.blacklist, &config.users] {
+            let _ = fs::remove_file(file);
+        }
+        built
+    }
+
+    /// Run the negotiation on what the client sends, returning its outcome and what the client got back
+    async fn negotiate_with(fwall: &Firewall, sent: &[u8]) -> (Result<SocksRequest>, Vec<u8>) {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(sent).await.unwrap();
+
+        let result = negotiate(&mut server, fwall, &Config::default(), "127.0.0.1".parse().unwrap()).await;
+        drop(server);
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+        (result, received)
+    }
+
+    #[tokio::test]
+    async fn test_socks5() {
+        let fwall = firewall("socks5", true);
+        let login = |password: &[u8]| [&[1, 5][..], b"alice", &[password.len() as u8], password].concat();
+        let request = [&[5, 1, 0, 3, 11][..], b"example.com", &[0x01, 0xbb]].concat();
+
+        let sent = [&[5, 2, 0, 2][..], &login(b"secret"), &request].concat();
+        let (result, received) = negotiate_with(&fwall, &sent).await;
+        assert_eq!(
+            result.unwrap(),
+            SocksRequest {
+                version: SOCKS5,
+                host: "example.com".to_owned(),
+                port: 443,
+                user: Some("alice".to_owned()),
+            }
+        );
+        assert_eq!(received, [5, USER_PASS, 1, 0]);
+
+        let sent = [&[5, 1, 2][..], &login(b"wrong"), &request].concat();
+        let (result, received) = negotiate_with(&fwall, &sent).await;
+        assert!(matches!(result, Err(ProxyError::ProxyAuthRequired)));
+        assert_eq!(received, [5, USER_PASS, 1, 1]);
+
+        // Authentication is required, so a client that only offers none is refused
+        let (result, received) = negotiate_with(&fwall, &[5, 1, 0]).await;
+        assert!(matches!(result, Err(ProxyError::ProxyAuthRequired)));
+        assert_eq!(received, [5, NO_ACCEPTABLE_METHOD]);
+    }
+
+    #[tokio::test]
+    async fn test_socks4a() {
+        let fwall = firewall("socks4a", false);
+        let sent = [&[4, 1, 0, 80, 0, 0, 0, 1][..], b"user\0example.com\0"].concat();
+        let (result, received) = negotiate_with(&fwall, &sent).await;
+        assert_eq!(
+            result.unwrap(),
+            SocksRequest {
+                version: SOCKS4,
+                host: "example.com".to_owned(),
+                port: 80,
+                user: None,
+            }
+        );
+        assert!(received.is_empty());
+
+        let (result, received) = negotiate_with(&firewall("socks4_auth", true), &sent).await;
+        assert!(matches!(result, Err(ProxyError::ProxyAuthRequired)));
+        assert_eq!(received[..2], [0, SOCKS4_REJECTED]);
+    }
+
+    #[tokio::test]
+    async fn test_reset_tunnel_logged() {
+        let shared = with_lists("reset", false, SharedFirewall::new);
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port().to_be_bytes();
+
+        let mut client = TcpStream::connect(proxy.local_addr().unwrap()).await.unwrap();
+        let (mut stream, _) = proxy.accept().await.unwrap();
+        let handler =
+            tokio::spawn(async move { process_connection(&mut stream, &shared, &Config::default()).await });
+
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0; 2];
+        client.read_exact(&mut method).await.unwrap();
+        client.write_all(&[5, 1, 0, 1, 127, 0, 0, 1, port[0], port[1]]).await.unwrap();
+        let mut reply = [0; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], SUCCEEDED);
+
+        let (mut server, _) = target.accept().await.unwrap();
+        client.write_all(&[b'x'; 31337]).await.unwrap();
+        let mut received = vec![0; 31337];
+        server.read_exact(&mut received).await.unwrap();
+
+        // The destination resets the connection, and what went through the tunnel before that is still logged
+        server.set_zero_linger().unwrap();
+        drop(server);
+        assert!(matches!(handler.await.unwrap(), Err(ProxyError::IO(_))));
+
+        let log = fs::read_to_string(LoggingConfig::default().event_log).unwrap();
+        assert!(log.contains("Total 31337 bytes exchanged between 127.0.0.1 and 127.0.0.1"));
+    }
+}
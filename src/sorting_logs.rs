@@ -1,88 +1,72 @@
-use std::net::{TcpListener, SocketAddr};
-use std::fs::{File, OpenOptions};
-use std::io::{ErrorKind, Write, Error};
-
 use std::fs;
-
-//log.txt is the log file generated
-const FILE_NAME: &str = "./log.txt";
-
-
-#[derive(Debug)]
-struct Logs {
-    file_addr: String,
-    date_time: String,
-    concatenatedAddrAndDateTime: String,
-    
+use std::io::{Error, Write};
+
+/// One line of the connection log, `address | date and time | result`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    pub addr: String,
+    pub date_time: String,
+    pub result: String,
 }
 
+/// Read the connection log at `path` and sort its entries by source address and then chronologically. Lines that
+/// aren't in the connection log format are skipped.
+pub fn sort_logs(path: &str) -> Result<Vec<LogEntry>, Error> {
+    let content = fs::read_to_string(path)?;
+    Ok(sort_entries(&content))
+}
 
-// This function is used to sort the log file entries ordered by source and chronology. 
-// Summary statistics like types of requests, number of failured/errors will be reported in Deliverable 2 as we develop further features.
-
- fn sort_logs()->Vec<Logs> {
-    
-    let content = fs::read_to_string(FILE_NAME).unwrap();
-    let mut entries: Vec<Logs> = Vec::new();
-    for line in content.lines(){
-        let logEntry:Vec<&str>= line.split("|").collect();
-       
-        let file_addr= logEntry[0].to_string();
-        let date_time= logEntry[1].to_string();
-
-
-        let concatenatedAddrAndDateTime= format!("{} {} ",file_addr , date_time);
-       
-        entries.push(Logs{
-            file_addr,
-            date_time,
-            concatenatedAddrAndDateTime,
-            }
-        );
-        
+fn sort_entries(content: &str) -> Vec<LogEntry> {
+    let mut entries: Vec<LogEntry> = Vec::new();
+    for line in content.lines() {
+        let log_entry: Vec<&str> = line.splitn(3, '|').map(|part| part.trim()).collect();
+        if log_entry.len() < 2 {
+            continue;
+        }
+
+        entries.push(LogEntry {
+            addr: log_entry[0].to_string(),
+            date_time: log_entry[1].to_string(),
+            result: log_entry.get(2).unwrap_or(&"").to_string(),
+        });
     }
 
-    entries.sort_by(|entry_a, entry_b| entry_a.concatenatedAddrAndDateTime.cmp(&entry_b.concatenatedAddrAndDateTime) );
+    // Times are written in the same format for every entry, so they sort chronologically as strings
+    entries.sort_by(|entry_a, entry_b| {
+        (&entry_a.addr, &entry_a.date_time).cmp(&(&entry_b.addr, &entry_b.date_time))
+    });
     entries
 }
 
-
-//This function writes the sorted log file.
- fn sorted_log_file(entries:Vec<Logs>) -> Result <(), Error> {
-   
-    let sorted_log_file = File::open("sortedLogs.txt");
-
-    match sorted_log_file {
-        Ok(file) => file,
-       
-        Err(error) => match error.kind() {
-            ErrorKind::NotFound => match File::create("sortedLogs.txt") {
-                Ok(fc) => fc,
-                Err(e) => panic!("Problem creating the sorted log file. Reason: {:?}", e),
-            },
-            other_error => {
-                panic!("Problem opening the sorted log file. Reason: {:?}", other_error);
-            },
-        },
-    };
-
-    let mut sorted_log_file = OpenOptions::new()
-    .write(true)
-    .append(true)
-    .open("sortedLogs.txt")
-    .unwrap();
-
-    for entry in entries{
-            write!(sorted_log_file, "{} | {}\n", entry.file_addr.to_string(), entry.date_time.to_string())?;      
+/// Write sorted entries in the connection log format
+pub fn write_sorted_logs(entries: &[LogEntry], out: &mut impl Write) -> Result<(), Error> {
+    for entry in entries {
+        writeln!(out, "{} | {} | {}", entry.addr, entry.date_time, entry.result)?;
     }
     Ok(())
 }
 
+#[cfg(test)]
+mod test_sorting_logs {
 
-fn main(){
-    let entries= sort_logs();
-    sorted_log_file(entries);
+    use super::*;
 
+    #[test]
+    fn test_sort_entries() {
+        let content = "127.0.0.2:5000 | 2024-01-02 10:00:00 | OK\n\
+            garbage\n\
+            127.0.0.1:6000 | 2024-01-03 10:00:00 | OK\n\
+            127.0.0.1:6000 | 2024-01-01 10:00:00 | Denied\n";
 
-}
+        let entries = sort_entries(content);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].date_time, "2024-01-01 10:00:00");
+        assert_eq!(entries[0].result, "Denied");
+        assert_eq!(entries[1].date_time, "2024-01-03 10:00:00");
+        assert_eq!(entries[2].addr, "127.0.0.2:5000");
 
+        let mut out = vec![];
+        write_sorted_logs(&entries[..1], &mut out).unwrap();
+        assert_eq!(out, b"127.0.0.1:6000 | 2024-01-01 10:00:00 | Denied\n");
+    }
+}
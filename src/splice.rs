@@ -0,0 +1,124 @@
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+/// Most bytes moved by a single splice call, the default capacity of a pipe
+const CHUNK: usize = 1 << 16;
+
+/// A pipe holding data spliced out of one side of a tunnel that hasn't been spliced into the other side yet. The data
+/// stays in the kernel the whole way, so it is never copied into or out of userspace.
+#[derive(Debug)]
+pub struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+    pending: usize,
+}
+
+impl Pipe {
+    pub fn new() -> io::Result<Pipe> {
+        let mut fds: [RawFd; 2] = [0; 2];
+
+        // SAFETY: fds has room for the two descriptors pipe2 writes
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: pipe2 just opened both descriptors and nothing else owns them
+        Ok(Pipe {
+            read: unsafe { OwnedFd::from_raw_fd(fds[0]) },
+            write: unsafe { OwnedFd::from_raw_fd(fds[1]) },
+            pending: 0,
+        })
+    }
+
+    /// Bytes in the pipe waiting to be spliced into the destination
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Splice whatever src has to read into the pipe without waiting. Returns 0 once src has been closed, and a
+    /// WouldBlock error if there is nothing to read yet. Only called while the pipe is empty, so a full pipe can't be
+    /// the reason it would block.
+    pub fn fill(&mut self, src: &TcpStream) -> io::Result<usize> {
+        let n = src.try_io(Interest::READABLE, || {
+            splice(src.as_raw_fd(), self.write.as_raw_fd(), CHUNK)
+        })?;
+        self.pending += n;
+        Ok(n)
+    }
+
+    /// Splice as much of the pipe into dst as it takes without waiting. Returns a WouldBlock error if dst can't take
+    /// anything yet.
+    pub fn drain(&mut self, dst: &TcpStream) -> io::Result<usize> {
+        let n = dst.try_io(Interest::WRITABLE, || {
+            splice(self.read.as_raw_fd(), dst.as_raw_fd(), self.pending)
+        })?;
+        self.pending -= n;
+        Ok(n)
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    // SAFETY: both descriptors are open for the duration of the call, and null offsets are required for sockets and
+    // pipes
+    let n = unsafe {
+        libc::splice(
+            from,
+            ptr::null_mut(),
+            to,
+            ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+
+    match n {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+#[cfg(test)]
+mod test_splice {
+
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_pipe_moves_data() {
+        let (mut a, src) = socket_pair().await;
+        let (dst, mut b) = socket_pair().await;
+        let mut pipe = Pipe::new().unwrap();
+
+        a.write_all(b"hello").await.unwrap();
+        src.readable().await.unwrap();
+        assert_eq!(pipe.fill(&src).unwrap(), 5);
+        assert_eq!(pipe.pending(), 5);
+
+        dst.writable().await.unwrap();
+        assert_eq!(pipe.drain(&dst).unwrap(), 5);
+        assert_eq!(pipe.pending(), 0);
+
+        let mut buf = [0u8; 5];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // Nothing left to read
+        assert_eq!(pipe.fill(&src).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        drop(a);
+        src.readable().await.unwrap();
+        assert_eq!(pipe.fill(&src).unwrap(), 0);
+    }
+}
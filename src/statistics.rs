@@ -1,54 +1,79 @@
-use std::fs::File;
 use std::fs;
 use std::{thread, time};
-use std::time::Duration;
-use regex::Regex;
 
+use crate::config::LoggingConfig;
 
-pub fn generate_statistics() {
-    File::create("./statistics.txt").expect("Unable to create statistics file.");
-    let wait_time = time::Duration::from_secs(5);
+pub fn generate_statistics(config: LoggingConfig) {
+    let wait_time = time::Duration::from_secs(config.statistics_interval_secs);
 
     loop {
-        let mut log = fs::read_to_string("./event_log.txt").expect("Unable to read log.txt");
-        let mut whitelist_deny = 0;
-        let mut blacklist_deny = 0;
-        let mut connection = 0;
-        let mut data_transfer = 0;
-        let mut proxy_server = 0;
-        let mut suspicious_activity = 0;
-        let mut uncategorised = 0;
-
-        for log_line in log.split("\n") {
-            if log_line.contains("Blacklist Deny") {
-                blacklist_deny += 1;
-            } else if log_line.contains("Whitelist Deny") {
-                whitelist_deny += 1;
-            } else if log_line.contains("Connection") {
-                connection += 1;
-            } else if log_line.contains("Data Transfer") {
-                data_transfer += 1;
-            } else if log_line.contains("Proxy Server") {
-                proxy_server += 1;
-            } else if log_line.contains("Suspicious Activity") {
-                suspicious_activity += 1;
-            } else {
-                uncategorised += 1;
-            }
+        if let Err(e) = fs::write(&config.statistics, current_statistics(&config)) {
+            eprintln!("Could not write to {}: {}", config.statistics, e);
         }
-        let statistics_text = format!(
-            "Total number of connections: {}\n\
-            Number of whitelist deny events: {}\n\
-            Number of blacklist deny events: {}\n\
-            Number of data transfer events: {}\n\
-            Number of proxy server events: {}\n\
-            Number of suspicious activities events: {}\n\
-            Number of uncategorized events: {}",
-            connection, whitelist_deny, blacklist_deny, data_transfer,
-            proxy_server, suspicious_activity, uncategorised);
-
-        fs::write("./statistics.txt", statistics_text).expect("Unable to write");
 
         thread::sleep(wait_time);
     }
-}
\ No newline at end of file
+}
+
+/// Statistics for the event log as it is now
+pub fn current_statistics(config: &LoggingConfig) -> String {
+    // The event log may not have been written yet, in which case there's nothing to count.
+    let log = fs::read_to_string(&config.event_log).unwrap_or_default();
+    count_events(&log)
+}
+
+/// Count the events of each kind in the contents of an event log
+fn count_events(log: &str) -> String {
+    let mut whitelist_deny = 0;
+    let mut blacklist_deny = 0;
+    let mut auth_deny = 0;
+    let mut policy_deny = 0;
+    let mut connection = 0;
+    let mut data_transfer = 0;
+    let mut proxy_server = 0;
+    let mut overloaded = 0;
+    let mut timeout = 0;
+    let mut suspicious_activity = 0;
+    let mut uncategorised = 0;
+
+    for log_line in log.split("\n") {
+        if log_line.contains("Blacklist Deny") {
+            blacklist_deny += 1;
+        } else if log_line.contains("Whitelist Deny") {
+            whitelist_deny += 1;
+        } else if log_line.contains("Auth Deny") {
+            auth_deny += 1;
+        } else if log_line.contains("Policy Deny") {
+            policy_deny += 1;
+        } else if log_line.contains("[Overloaded]") {
+            overloaded += 1;
+        } else if log_line.contains("[Timeout]") {
+            timeout += 1;
+        } else if log_line.contains("Connection") {
+            connection += 1;
+        } else if log_line.contains("Data Transfer") {
+            data_transfer += 1;
+        } else if log_line.contains("Proxy Server") {
+            proxy_server += 1;
+        } else if log_line.contains("Suspicious Activity") {
+            suspicious_activity += 1;
+        } else {
+            uncategorised += 1;
+        }
+    }
+
+    format!(
+        "Total number of connections: {}\n\
+        Number of whitelist deny events: {}\n\
+        Number of blacklist deny events: {}\n\
+        Number of authentication deny events: {}\n\
+        Number of policy deny events: {}\n\
+        Number of data transfer events: {}\n\
+        Number of proxy server events: {}\n\
+        Number of connections turned away while overloaded: {}\n\
+        Number of timeouts: {}\n\
+        Number of suspicious activities events: {}\n\
+        Number of uncategorized events: {}",
+        connection, whitelist_deny, blacklist_deny, auth_deny, policy_deny, data_transfer,
+        proxy_server, overloaded, timeout, suspicious_activity, uncategorised)
+}